@@ -0,0 +1,663 @@
+use crate::chat::{Chat, ChatKind};
+use crate::packet_utils::Buf;
+use crate::physics;
+use crate::protocol::Clientbound;
+use crate::settings::Settings;
+use crate::states::play;
+use crate::{Bot, Context, Error};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// What a bot does once it is in the play state.
+///
+/// Every bot gets its own instance, so implementations can keep per bot state. Packets are sent
+/// with `bot.send_packet(buf, ctx)`, and the blocks around the bot are looked up in `ctx.world`.
+pub trait Behaviour {
+    /// The server sent the Login (play) packet
+    fn on_join(&mut self, _bot: &mut Bot, _ctx: &mut Context) {}
+
+    /// Called every tick while the bot is in the play state, `tick` counts the worker's ticks
+    fn on_tick(&mut self, _bot: &mut Bot, _ctx: &mut Context, _tick: u32) {}
+
+    /// The bot received a chat message, after it has been counted
+    fn on_chat(&mut self, _bot: &mut Bot, _ctx: &mut Context, _chat: &Chat) {}
+
+    /// Called for every known play packet before the bot handles it, the reader index of
+    /// `buffer` is put back afterwards
+    fn on_packet(
+        &mut self,
+        _bot: &mut Bot,
+        _ctx: &mut Context,
+        _packet: Clientbound,
+        _buffer: &mut Buf,
+    ) {
+    }
+}
+
+/// Runs a hook of the bot's behaviour, which is taken out of the bot while the hook runs
+pub fn run(bot: &mut Bot, hook: impl FnOnce(&mut dyn Behaviour, &mut Bot)) {
+    if let Some(mut behaviour) = bot.behaviour.take() {
+        hook(behaviour.as_mut(), bot);
+        bot.behaviour = Some(behaviour);
+    }
+}
+
+/// The behaviours a scenario can pick from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Walk around and perform a random action every `action_tick` ticks
+    Random,
+    /// Stay where the server put the bot and only answer keep alives
+    Idle,
+    /// Run the commands of the `[commands]` table once the bot spawned and on a timer
+    Commands,
+}
+
+impl FromStr for Kind {
+    type Err = Error;
+
+    fn from_str(kind: &str) -> Result<Kind, Error> {
+        match kind {
+            "random" => Ok(Kind::Random),
+            "idle" => Ok(Kind::Idle),
+            "commands" => Ok(Kind::Commands),
+            _ => Err(format!(
+                "unknown behaviour {}, expected random, idle or commands",
+                kind
+            )
+            .into()),
+        }
+    }
+}
+
+/// Share of the bots running one behaviour
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub behaviour: Kind,
+    /// Bots are spread over the groups in proportion to their weights
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl FromStr for Group {
+    type Err = Error;
+
+    /// Parses `<behaviour>` or `<behaviour>:<weight>`
+    fn from_str(group: &str) -> Result<Group, Error> {
+        let (behaviour, weight) = match group.split_once(':') {
+            Some((behaviour, weight)) => (
+                behaviour,
+                weight
+                    .parse()
+                    .map_err(|_| format!("invalid weight in behaviour group {}", group))?,
+            ),
+            None => (group, default_weight()),
+        };
+        Ok(Group {
+            behaviour: behaviour.parse()?,
+            weight,
+        })
+    }
+}
+
+/// Creates the behaviour of the bot with the run wide `index`.
+///
+/// Groups get consecutive bots in proportion to their weight, so weights of 3 and 1 give the
+/// first group bots 0, 1 and 2 and the second group bot 3, and so on.
+pub fn for_bot(settings: &Settings, index: u32, rng: &mut StdRng) -> Box<dyn Behaviour> {
+    let total: u32 = settings.groups.iter().map(|group| group.weight).sum();
+    let mut slot = if total > 0 { index % total } else { 0 };
+    let kind = settings
+        .groups
+        .iter()
+        .find(|group| {
+            let found = slot < group.weight;
+            slot = slot.saturating_sub(group.weight);
+            found
+        })
+        .map_or(Kind::Random, |group| group.behaviour);
+
+    match kind {
+        Kind::Random => Box::new(Random::new(&settings.actions, rng)),
+        Kind::Idle => Box::new(Idle),
+        Kind::Commands => Box::new(Commands::new(index)),
+    }
+}
+
+pub struct Idle;
+
+impl Behaviour for Idle {}
+
+/// One of the things the `random` behaviour does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Walk around with the physics of `physics::tick` and send the movement
+    Move,
+    Chat,
+    Swing,
+    Sneak,
+    Sprint,
+    HeldItem,
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::Move,
+        Action::Chat,
+        Action::Swing,
+        Action::Sneak,
+        Action::Sprint,
+        Action::HeldItem,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Action::Move => "move",
+            Action::Chat => "chat",
+            Action::Swing => "swing",
+            Action::Sneak => "sneak",
+            Action::Sprint => "sprint",
+            Action::HeldItem => "held_item",
+        }
+    }
+}
+
+/// How often an action happens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActionRate {
+    /// Chance of being picked when the bot acts every `action_tick` ticks, relative to the
+    /// other actions
+    pub weight: u32,
+    /// Run on its own timer every `every` ticks instead of being picked
+    pub every: Option<u32>,
+}
+
+impl Default for ActionRate {
+    fn default() -> Self {
+        ActionRate {
+            weight: 1,
+            every: None,
+        }
+    }
+}
+
+/// Traffic mix of the `random` behaviour
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActionMix {
+    #[serde(rename = "move")]
+    pub movement: ActionRate,
+    pub chat: ActionRate,
+    pub swing: ActionRate,
+    pub sneak: ActionRate,
+    pub sprint: ActionRate,
+    pub held_item: ActionRate,
+    /// Start every bot's timers at a random tick, otherwise they are offset by the bot id
+    pub random_phase: bool,
+}
+
+impl Default for ActionMix {
+    fn default() -> Self {
+        ActionMix {
+            movement: ActionRate {
+                weight: 0,
+                every: Some(1),
+            },
+            chat: ActionRate::default(),
+            swing: ActionRate::default(),
+            sneak: ActionRate::default(),
+            sprint: ActionRate::default(),
+            held_item: ActionRate::default(),
+            random_phase: false,
+        }
+    }
+}
+
+impl ActionMix {
+    pub fn rate(&self, action: Action) -> &ActionRate {
+        match action {
+            Action::Move => &self.movement,
+            Action::Chat => &self.chat,
+            Action::Swing => &self.swing,
+            Action::Sneak => &self.sneak,
+            Action::Sprint => &self.sprint,
+            Action::HeldItem => &self.held_item,
+        }
+    }
+
+    /// Applies `<action>:<weight>` or `<action>:<weight>:<every>`
+    pub fn set(&mut self, spec: &str) -> Result<(), Error> {
+        let invalid = || {
+            format!(
+                "invalid action {}, expected <action>:<weight>[:<every>] with action one of {}",
+                spec,
+                Action::ALL.map(Action::name).join(", ")
+            )
+        };
+        let mut parts = spec.split(':');
+        let action = parts
+            .next()
+            .and_then(|name| {
+                Action::ALL
+                    .iter()
+                    .copied()
+                    .find(|action| action.name() == name)
+            })
+            .ok_or_else(invalid)?;
+        let weight = parts
+            .next()
+            .and_then(|weight| weight.parse().ok())
+            .ok_or_else(invalid)?;
+        let every = match parts.next() {
+            Some(every) => Some(every.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return Err(invalid().into());
+        }
+
+        let rate = match action {
+            Action::Move => &mut self.movement,
+            Action::Chat => &mut self.chat,
+            Action::Swing => &mut self.swing,
+            Action::Sneak => &mut self.sneak,
+            Action::Sprint => &mut self.sprint,
+            Action::HeldItem => &mut self.held_item,
+        };
+        *rate = ActionRate { weight, every };
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        for action in Action::ALL {
+            if self.rate(action).every == Some(0) {
+                return Err(format!("action {} can't run every 0 ticks", action.name()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ticks of movement simulated at once when `move` runs less often than every tick
+const MAX_CATCH_UP: u32 = 20;
+
+/// Walks around and performs random actions, see `ActionMix`
+pub struct Random {
+    /// Offset of the timer of every action, and of the weighted pick last
+    phase: [u32; Action::ALL.len() + 1],
+    /// Tick of the last move, the physics catch up on the ticks in between
+    last_move: Option<u32>,
+}
+
+impl Random {
+    pub fn new(mix: &ActionMix, rng: &mut StdRng) -> Random {
+        let mut phase = [0; Action::ALL.len() + 1];
+        if mix.random_phase {
+            for offset in phase.iter_mut() {
+                *offset = rng.gen::<u16>() as u32;
+            }
+        }
+        Random {
+            phase,
+            last_move: None,
+        }
+    }
+
+    /// Simulates the ticks since the last move and sends the movement, if there was any
+    fn walk(&mut self, bot: &mut Bot, ctx: &mut Context, tick: u32) {
+        let ticks = self
+            .last_move
+            .map_or(1, |last| tick.wrapping_sub(last).clamp(1, MAX_CATCH_UP));
+        self.last_move = Some(tick);
+        for _ in 0..ticks {
+            physics::tick(bot, &ctx.world);
+        }
+        if let Some(movement) = bot.body.movement(bot.x, bot.y, bot.z) {
+            bot.send_packet(play::write_movement(ctx.protocol, bot, movement), ctx);
+        }
+    }
+
+    fn perform(&mut self, action: Action, bot: &mut Bot, ctx: &mut Context, tick: u32) {
+        let rng = &mut bot.rng;
+        let packet = match action {
+            Action::Move => return self.walk(bot, ctx, tick),
+            Action::Chat => {
+                ctx.metrics.chat_sent += 1;
+                let message = ctx.settings.messages.choose(rng).unwrap();
+                play::write_chat_message(ctx.protocol, message)
+            }
+            Action::Swing => play::write_animation(ctx.protocol, rng.gen()),
+            // Start or stop sneaking
+            Action::Sneak => {
+                bot.body.sneaking = rng.gen();
+                let action_id = if bot.body.sneaking { 0 } else { 1 };
+                play::write_entity_action(ctx.protocol, bot.entity_id, action_id, 0)
+            }
+            // Start or stop sprinting
+            Action::Sprint => {
+                bot.body.sprinting = rng.gen();
+                let action_id = if bot.body.sprinting { 3 } else { 4 };
+                play::write_entity_action(ctx.protocol, bot.entity_id, action_id, 0)
+            }
+            Action::HeldItem => play::write_held_slot(ctx.protocol, rng.gen_range(0..9)),
+        };
+        bot.send_packet(packet, ctx);
+    }
+
+    /// Picks one of the actions without their own timer, by weight
+    fn pick(mix: &ActionMix, rng: &mut StdRng) -> Option<Action> {
+        let picked = || {
+            Action::ALL
+                .iter()
+                .copied()
+                .filter(|action| mix.rate(*action).every.is_none())
+        };
+        let total: u32 = picked().map(|action| mix.rate(action).weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = rng.gen_range(0..total);
+        picked().find(|action| {
+            let weight = mix.rate(*action).weight;
+            let found = roll < weight;
+            roll = roll.saturating_sub(weight);
+            found
+        })
+    }
+}
+
+impl Behaviour for Random {
+    fn on_tick(&mut self, bot: &mut Bot, ctx: &mut Context, tick: u32) {
+        if !bot.teleported {
+            return;
+        }
+
+        let settings = ctx.settings.clone();
+        let mut mix = settings.actions.clone();
+        // Bots that don't move still do everything else
+        if !settings.movement {
+            mix.movement = ActionRate {
+                weight: 0,
+                every: None,
+            };
+        }
+        let tick = tick.wrapping_add(bot.id);
+        for (index, action) in Action::ALL.iter().copied().enumerate() {
+            if let Some(every) = mix.rate(action).every {
+                if tick.wrapping_add(self.phase[index]) % every == 0 {
+                    self.perform(action, bot, ctx, tick);
+                }
+            }
+        }
+
+        let phase = self.phase[Action::ALL.len()];
+        if tick.wrapping_add(phase) % settings.action_tick == 0 {
+            if let Some(action) = Random::pick(&mix, &mut bot.rng) {
+                self.perform(action, bot, ctx, tick);
+            }
+        }
+    }
+}
+
+/// Commands of the `commands` behaviour, written without the leading slash. `{name}` and
+/// `{index}` are replaced by the bot's name and its run wide index.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandScript {
+    /// Run in order once the bot spawned
+    pub on_join: Vec<String>,
+    /// Run in order every `every` ticks
+    pub on_timer: Vec<String>,
+    pub every: u32,
+    /// System chat containing one of these is counted as an error answer to a command
+    pub errors: Vec<String>,
+}
+
+impl Default for CommandScript {
+    fn default() -> Self {
+        CommandScript {
+            on_join: Vec::new(),
+            on_timer: Vec::new(),
+            every: 200,
+            // Vanilla sends translatable errors, which the bots see as their keys, the others
+            // are the plain text errors of Spigot and Paper
+            errors: vec![
+                "command.unknown.".to_owned(),
+                "command.expected.".to_owned(),
+                "argument.".to_owned(),
+                "parsing.".to_owned(),
+                "Unknown command".to_owned(),
+                "do not have permission".to_owned(),
+            ],
+        }
+    }
+}
+
+impl CommandScript {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.every == 0 {
+            return Err("commands can't run every 0 ticks".into());
+        }
+        if self
+            .on_join
+            .iter()
+            .chain(&self.on_timer)
+            .any(|command| command.trim_start_matches('/').is_empty())
+        {
+            return Err("commands can't be empty".into());
+        }
+        Ok(())
+    }
+}
+
+/// Runs the commands of `CommandScript` and counts the errors the server answers with
+pub struct Commands {
+    /// Run wide index of the bot, for `{index}`
+    index: u32,
+    /// The join commands are sent once the bot spawned
+    joined: bool,
+}
+
+impl Commands {
+    pub fn new(index: u32) -> Commands {
+        Commands {
+            index,
+            joined: false,
+        }
+    }
+
+    fn send(&self, commands: &[String], bot: &mut Bot, ctx: &mut Context) {
+        for command in commands {
+            let command = command
+                .trim_start_matches('/')
+                .replace("{name}", &bot.name)
+                .replace("{index}", &self.index.to_string());
+            ctx.metrics.commands_sent += 1;
+            bot.send_packet(play::write_chat_command(ctx.protocol, &command), ctx);
+        }
+    }
+}
+
+impl Behaviour for Commands {
+    fn on_join(&mut self, _bot: &mut Bot, _ctx: &mut Context) {
+        self.joined = false;
+    }
+
+    fn on_tick(&mut self, bot: &mut Bot, ctx: &mut Context, tick: u32) {
+        if !bot.teleported {
+            return;
+        }
+
+        let settings = ctx.settings.clone();
+        let script = &settings.commands;
+        if !self.joined {
+            self.joined = true;
+            self.send(&script.on_join, bot, ctx);
+        }
+        if tick.wrapping_add(bot.id) % script.every == 0 {
+            self.send(&script.on_timer, bot, ctx);
+        }
+    }
+
+    /// Errors are counted by the pattern they matched, the messages themselves often contain
+    /// names or coordinates
+    fn on_chat(&mut self, _bot: &mut Bot, ctx: &mut Context, chat: &Chat) {
+        if chat.kind != ChatKind::System {
+            return;
+        }
+        let matched = ctx
+            .settings
+            .commands
+            .errors
+            .iter()
+            .find(|error| chat.text.contains(error.as_str()));
+        if let Some(pattern) = matched {
+            ctx.metrics.record_command_error(pattern);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Serverbound, PROTOCOLS};
+    use crate::ProtocolState;
+
+    #[test]
+    fn actions_are_set_by_name() {
+        let mut mix = ActionMix::default();
+        mix.set("chat:5").unwrap();
+        mix.set("held_item:0:40").unwrap();
+        mix.set("move:2").unwrap();
+        assert_eq!(
+            mix.chat,
+            ActionRate {
+                weight: 5,
+                every: None
+            }
+        );
+        assert_eq!(
+            mix.held_item,
+            ActionRate {
+                weight: 0,
+                every: Some(40)
+            }
+        );
+        // A rate without `every` drops the old one
+        assert_eq!(
+            *mix.rate(Action::Move),
+            ActionRate {
+                weight: 2,
+                every: None
+            }
+        );
+        assert_eq!(mix.swing, ActionRate::default());
+        assert!(mix.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_actions_are_an_error() {
+        let mut mix = ActionMix::default();
+        for spec in [
+            "",
+            "chat",
+            "chat:",
+            "chat:-1",
+            "chat:1:x",
+            "chat:1:2:3",
+            "jump:1",
+            "Chat:1",
+        ] {
+            assert!(mix.set(spec).is_err(), "{}", spec);
+        }
+        assert_eq!(mix, ActionMix::default());
+
+        mix.set("sneak:1:0").unwrap();
+        assert!(mix.validate().is_err());
+    }
+
+    #[test]
+    fn groups_parse() {
+        assert_eq!(
+            "random:3".parse::<Group>().unwrap(),
+            Group {
+                behaviour: Kind::Random,
+                weight: 3
+            }
+        );
+        assert_eq!(
+            "idle".parse::<Group>().unwrap(),
+            Group {
+                behaviour: Kind::Idle,
+                weight: 1
+            }
+        );
+        assert!("random:x".parse::<Group>().is_err());
+        assert!("dance".parse::<Group>().is_err());
+    }
+
+    #[test]
+    fn commands_are_sent_on_join_and_on_the_timer() {
+        for protocol in PROTOCOLS {
+            let mut settings = Settings::default();
+            settings.commands.on_join = vec!["/msg {name} hi".to_owned(), "tp {index}".to_owned()];
+            settings.commands.on_timer = vec!["list".to_owned()];
+            settings.commands.every = 20;
+            let mut ctx = Context::for_protocol(protocol, settings);
+            let (mut bot, mut peer) = Bot::paired(ProtocolState::Play);
+            let mut commands = Commands::new(42);
+
+            // Nothing before the bot spawned
+            commands.on_tick(&mut bot, &mut ctx, 3);
+            assert_eq!(ctx.metrics.commands_sent, 0);
+            bot.teleported = true;
+            for tick in 3..=20 {
+                commands.on_tick(&mut bot, &mut ctx, tick);
+            }
+            assert_eq!(ctx.metrics.commands_sent, 3);
+
+            let id = protocol.serverbound(ProtocolState::Play, Serverbound::ChatCommand);
+            for expected in ["msg Bot_0 hi", "tp 42", "list"] {
+                let mut sent = Bot::sent(&mut peer);
+                assert_eq!(sent.read_var_u32().0, id);
+                assert_eq!(sent.read_sized_string().unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn command_errors_are_counted_by_pattern() {
+        let mut ctx = Context::for_protocol(&PROTOCOLS[0], Settings::default());
+        let (mut bot, _peer) = Bot::paired(ProtocolState::Play);
+        let mut commands = Commands::new(0);
+        for (kind, text) in [
+            (
+                ChatKind::System,
+                "Unknown command. Type \"/help\" for help.",
+            ),
+            (ChatKind::System, "Unknown command: tpa"),
+            (ChatKind::System, "command.unknown.command"),
+            (ChatKind::System, "Teleported Bot_0"),
+            (ChatKind::Player, "Unknown command"),
+        ] {
+            let chat = Chat {
+                kind,
+                sender: None,
+                text: text.to_owned(),
+            };
+            commands.on_chat(&mut bot, &mut ctx, &chat);
+        }
+        assert_eq!(ctx.metrics.command_errors, 3);
+        assert_eq!(ctx.metrics.command_error_patterns["Unknown command"], 2);
+        assert_eq!(ctx.metrics.command_error_patterns["command.unknown."], 1);
+    }
+}
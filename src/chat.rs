@@ -0,0 +1,126 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Starts the chat messages of the latency probe
+const PROBE_PREFIX: &str = "mcbot-probe";
+
+/// The packet a chat message arrived in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatKind {
+    /// Sent by the server itself, command feedback and join messages among others
+    System,
+    /// Sent by a player, with the signed message body
+    Player,
+    /// Sent by the server on behalf of a player, for example by `/say`
+    Disguised,
+}
+
+impl ChatKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ChatKind::System => "system",
+            ChatKind::Player => "player",
+            ChatKind::Disguised => "disguised",
+        }
+    }
+}
+
+/// A chat message received by a bot, with its text components flattened to plain text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chat {
+    pub kind: ChatKind,
+    /// Display name of the player that sent it, `None` for system messages and chat types the
+    /// bot can't read
+    pub sender: Option<String>,
+    pub text: String,
+}
+
+impl fmt::Display for Chat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.sender {
+            Some(sender) => write!(f, "<{}> {}", sender, self.text),
+            None => write!(f, "{}", self.text),
+        }
+    }
+}
+
+fn micros_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Chat message of the latency probe, carrying the sender's name and a sequence number, which
+/// together identify it, and the time it was sent
+pub fn probe_message(sender: &str, sequence: u32) -> String {
+    format!(
+        "{} {} {} {}",
+        PROBE_PREFIX,
+        sender,
+        sequence,
+        micros_since_epoch()
+    )
+}
+
+/// Time since a probe message in `text` was sent, `None` if the text doesn't carry one or the
+/// probe was sent by `receiver` itself.
+///
+/// The probe is looked for anywhere in the text, so servers that decorate chat still work.
+pub fn probe_latency(text: &str, receiver: &str) -> Option<Duration> {
+    let start = text.find(PROBE_PREFIX)?;
+    let mut fields = text[start + PROBE_PREFIX.len()..].split_whitespace();
+    let sender = fields.next()?;
+    let _sequence: u32 = fields.next()?.parse().ok()?;
+    let sent: u64 = fields.next()?.parse().ok()?;
+    if sender == receiver {
+        return None;
+    }
+    Some(Duration::from_micros(
+        micros_since_epoch().saturating_sub(sent),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_are_found_in_decorated_chat() {
+        let probe = probe_message("Bot_1", 7);
+        assert!(probe_latency(&probe, "Bot_2").unwrap() < Duration::from_secs(1));
+        let decorated = format!("[Server] <Bot_1> {} (edited)", probe);
+        assert!(probe_latency(&decorated, "Bot_2").is_some());
+        // Bots don't measure their own probes
+        assert_eq!(probe_latency(&probe, "Bot_1"), None);
+    }
+
+    #[test]
+    fn other_chat_is_not_a_probe() {
+        for text in [
+            "",
+            "hello",
+            "mcbot-probe",
+            "mcbot-probe Bot_1",
+            "mcbot-probe Bot_1 x 100",
+            "mcbot-probe Bot_1 1 -5",
+        ] {
+            assert_eq!(probe_latency(text, "Bot_2"), None, "{}", text);
+        }
+        // Clocks that disagree don't give a negative latency
+        let future = format!("mcbot-probe Bot_1 1 {}", u64::MAX);
+        assert_eq!(probe_latency(&future, "Bot_2"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn chat_is_shown_with_its_sender() {
+        let mut chat = Chat {
+            kind: ChatKind::Player,
+            sender: Some("Bot_1".to_owned()),
+            text: "hi".to_owned(),
+        };
+        assert_eq!(chat.to_string(), "<Bot_1> hi");
+        chat.sender = None;
+        assert_eq!(chat.to_string(), "hi");
+    }
+}
@@ -0,0 +1,117 @@
+use crate::Error;
+use aes::Aes128;
+use cfb8::cipher::consts::U1;
+use cfb8::cipher::inout::InOutBuf;
+use cfb8::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{Pkcs1v15Encrypt, RsaPublicKey};
+use sha1::{Digest, Sha1};
+
+/// AES-128-CFB8 stream cipher state of an encrypted connection
+pub struct Encryption {
+    encryptor: cfb8::Encryptor<Aes128>,
+    decryptor: cfb8::Decryptor<Aes128>,
+}
+
+impl Encryption {
+    /// The shared secret is used as both the key and the iv
+    pub fn new(shared_secret: &[u8; 16]) -> Encryption {
+        Encryption {
+            encryptor: cfb8::Encryptor::new(shared_secret.into(), shared_secret.into()),
+            decryptor: cfb8::Decryptor::new(shared_secret.into(), shared_secret.into()),
+        }
+    }
+
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        // CFB8 works on one byte blocks so there is never a tail
+        let (blocks, _) = InOutBuf::from(data).into_chunks::<U1>();
+        self.encryptor.encrypt_blocks_inout_mut(blocks);
+    }
+
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        let (blocks, _) = InOutBuf::from(data).into_chunks::<U1>();
+        self.decryptor.decrypt_blocks_inout_mut(blocks);
+    }
+}
+
+/// Encrypts `data` with the DER encoded public key sent in the Encryption Request
+pub fn encrypt_with_public_key(public_key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let key = RsaPublicKey::from_public_key_der(public_key)?;
+    Ok(key.encrypt(&mut rand::thread_rng(), Pkcs1v15Encrypt, data)?)
+}
+
+/// The server id hash sent to the session server, a SHA-1 digest printed as a signed hex number
+pub fn server_hash(server_id: &str, shared_secret: &[u8], public_key: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(server_id.as_bytes());
+    hasher.update(shared_secret);
+    hasher.update(public_key);
+    let mut digest: [u8; 20] = hasher.finalize().into();
+
+    // Negative digests are printed as a minus sign followed by their two's complement
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        let mut carry = true;
+        for byte in digest.iter_mut().rev() {
+            *byte = !*byte;
+            if carry {
+                let (sum, overflow) = byte.overflowing_add(1);
+                *byte = sum;
+                carry = overflow;
+            }
+        }
+    }
+
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let hex = hex.trim_start_matches('0');
+    if negative {
+        format!("-{}", hex)
+    } else {
+        hex.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_hashes_match_vanilla() {
+        assert_eq!(
+            server_hash("Notch", &[], &[]),
+            "4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48"
+        );
+        assert_eq!(
+            server_hash("jeb_", &[], &[]),
+            "-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1"
+        );
+        assert_eq!(
+            server_hash("simon", &[], &[]),
+            "88e16a1019277b15d58faf0541e11910eb756f6"
+        );
+        // The parts are hashed one after another
+        assert_eq!(
+            server_hash("", b"No", b"tch"),
+            server_hash("Notch", &[], &[])
+        );
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypts() {
+        let secret = [7; 16];
+        let mut data = b"hello, split in two".to_vec();
+        let mut sender = Encryption::new(&secret);
+        let (first, second) = data.split_at_mut(5);
+        sender.encrypt(first);
+        sender.encrypt(second);
+        assert_ne!(&data[..], b"hello, split in two");
+        let mut receiver = Encryption::new(&secret);
+        receiver.decrypt(&mut data);
+        assert_eq!(&data[..], b"hello, split in two");
+    }
+
+    #[test]
+    fn invalid_public_keys_are_an_error() {
+        assert!(encrypt_with_public_key(&[0x30, 0x03, 1, 2, 3], &[0; 16]).is_err());
+    }
+}
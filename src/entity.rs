@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+/// An entity the server told a bot about
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entity {
+    pub uuid: u128,
+    /// Id in the entity type registry, which differs between protocol versions
+    pub kind: u32,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    /// Degrees
+    pub yaw: f32,
+    pub pitch: f32,
+    pub on_ground: bool,
+    /// Flags of the first metadata entry: 0x01 on fire, 0x02 sneaking, 0x08 sprinting,
+    /// 0x10 swimming, 0x20 invisible, 0x40 glowing and 0x80 flying with an elytra
+    pub flags: u8,
+}
+
+impl Entity {
+    pub fn distance_squared(&self, x: f64, y: f64, z: f64) -> f64 {
+        (self.x - x).powi(2) + (self.y - y).powi(2) + (self.z - z).powi(2)
+    }
+}
+
+/// The entities a bot can see, by entity id
+#[derive(Debug, Clone, Default)]
+pub struct Entities {
+    entities: HashMap<u32, Entity>,
+}
+
+impl Entities {
+    pub fn spawn(&mut self, id: u32, entity: Entity) {
+        self.entities.insert(id, entity);
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.entities.remove(&id);
+    }
+
+    pub fn clear(&mut self) {
+        self.entities.clear();
+    }
+
+    /// Updates for entities the bot doesn't know about are ignored
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Entity> {
+        self.entities.get_mut(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Entity)> {
+        self.entities.iter().map(|(id, entity)| (*id, entity))
+    }
+
+    /// The entity closest to the given position that `filter` accepts
+    pub fn nearest(
+        &self,
+        x: f64,
+        y: f64,
+        z: f64,
+        filter: impl Fn(&Entity) -> bool,
+    ) -> Option<(u32, &Entity)> {
+        self.iter()
+            .filter(|(_, entity)| filter(entity))
+            .min_by(|(_, a), (_, b)| {
+                a.distance_squared(x, y, z)
+                    .total_cmp(&b.distance_squared(x, y, z))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(kind: u32, x: f64) -> Entity {
+        Entity {
+            uuid: kind as u128,
+            kind,
+            x,
+            y: 64.0,
+            z: 0.0,
+            yaw: 0.0,
+            pitch: 0.0,
+            on_ground: true,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn nearest_entity_passes_the_filter() {
+        let mut entities = Entities::default();
+        entities.spawn(1, entity(1, 10.0));
+        entities.spawn(2, entity(2, -3.0));
+        entities.spawn(3, entity(1, 5.0));
+        assert_eq!(entities.nearest(0.0, 64.0, 0.0, |_| true).unwrap().0, 2);
+        assert_eq!(
+            entities
+                .nearest(0.0, 64.0, 0.0, |entity| entity.kind == 1)
+                .unwrap()
+                .0,
+            3
+        );
+        assert_eq!(entities.nearest(0.0, 64.0, 0.0, |_| false), None);
+    }
+
+    #[test]
+    fn entities_are_replaced_and_removed_by_id() {
+        let mut entities = Entities::default();
+        entities.spawn(1, entity(1, 0.0));
+        entities.spawn(1, entity(2, 0.0));
+        assert_eq!(entities.len(), 1);
+        entities.get_mut(1).unwrap().x = 4.0;
+        assert_eq!(
+            entities.iter().next(),
+            Some((
+                1,
+                &Entity {
+                    x: 4.0,
+                    ..entity(2, 0.0)
+                }
+            ))
+        );
+        assert_eq!(entities.get_mut(5), None);
+        entities.remove(5);
+        entities.remove(1);
+        assert_eq!(entities.len(), 0);
+        entities.spawn(2, entity(1, 0.0));
+        entities.clear();
+        assert_eq!(entities.iter().count(), 0);
+    }
+}
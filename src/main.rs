@@ -1,105 +1,148 @@
+mod behaviour;
+mod chat;
+mod encryption;
+mod entity;
+mod metrics;
+mod nbt;
 mod net;
 mod packet_processors;
 mod packet_utils;
+mod phases;
+mod physics;
+mod prometheus;
+mod protocol;
+mod reconnect;
+mod report;
+mod session;
+mod settings;
 mod states;
+mod text;
+mod world;
 
+use crate::behaviour::Behaviour;
+use crate::encryption::Encryption;
+use crate::entity::Entities;
+use crate::metrics::{Metrics, MetricsHub, Traffic};
 use crate::packet_utils::Buf;
-use crate::states::login;
+use crate::physics::Body;
+use crate::protocol::Protocol;
+use crate::report::Recorder;
+use crate::settings::{Mode, Settings};
+use crate::states::login::{self, Joins, PendingJoin};
+use crate::world::{ChunkPos, World};
 use libdeflater::{CompressionLvl, Compressor, Decompressor};
 use mio::net::TcpStream;
 use mio::{event, Events, Interest, Poll, Registry, Token};
-use rand::prelude::*;
-use states::play;
-use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use states::{play, status};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
 use std::io;
 use std::io::{Read, Write};
-use std::net::SocketAddr;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::{env, net::ToSocketAddrs};
-use uuid::Uuid;
 
 #[cfg(unix)]
 use {mio::net::UnixStream, std::path::PathBuf};
 
-// This rate limits the join rate of the bots
-// Increasing it will cause the bots to join more quickly
-const AVG_JOINS_PER_TICK: f64 = 5.0;
-
-const SHOULD_MOVE: bool = true;
-const MESSAGES: &[&str] = &["This is a chat message!", "Wow", "Server = on?"];
-
-#[cfg(unix)]
-const UDS_PREFIX: &str = "unix://";
-const PROTOCOL_VERSION: u32 = 767;
-
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
+    let name = args.first().map(String::as_str).unwrap_or("rust-mc-bot");
 
-    if args.len() < 3 {
-        let name = args.get(0).unwrap();
-        #[cfg(unix)]
-        println!("usage: {} <ip:port or path> <count> [threads]", name);
-        #[cfg(not(unix))]
-        println!("usage: {} <ip:port> <count> [threads]", name);
-        println!("example: {} localhost:25565 500", name);
-        #[cfg(unix)]
-        println!("example: {} unix:///path/to/socket 500", name);
+    if args.len() < 2 {
+        settings::print_usage(name);
         return Ok(());
     }
 
-    let arg1 = args.get(1).unwrap();
-    let arg2 = args.get(2).unwrap();
-    let arg3 = args.get(3);
+    let mut settings = match Settings::from_args(&args) {
+        Ok(Some(settings)) => settings,
+        Ok(None) => {
+            settings::print_usage(name);
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            eprintln!("run {} --help for usage", name);
+            std::process::exit(2);
+        }
+    };
 
-    let mut addrs = None;
+    let addrs = match settings.address() {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(2);
+        }
+    };
 
-    #[cfg(unix)]
-    if let Some(unix_socket) = arg1.strip_prefix(UDS_PREFIX) {
-        addrs = Some(Address::UNIX(PathBuf::from(unix_socket.to_owned())));
+    if settings.detect_protocol {
+        match protocol::detect(&addrs, settings.protocol_version) {
+            Ok(protocol_version) => settings.protocol_version = protocol_version,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                std::process::exit(2);
+            }
+        }
     }
 
-    if addrs.is_none() {
-        let mut parts = arg1.split(':');
-        let ip = parts.next().expect("no ip provided");
-        let port = parts
-            .next()
-            .map(|port_string| port_string.parse().expect("invalid port"))
-            .unwrap_or(25565u16);
+    if let Some(mock_address) = settings.mock_session_server {
+        match session::start_mock(mock_address) {
+            // The bots join through the mock unless another session server was given
+            Ok(url) => {
+                settings.session_server.get_or_insert(url);
+            }
+            Err(err) => {
+                eprintln!(
+                    "error: could not start the mock session server on {}: {}",
+                    mock_address, err
+                );
+                std::process::exit(2);
+            }
+        }
+    }
 
-        let server = (ip, port)
-            .to_socket_addrs()
-            .expect("Not a socket address")
-            .next()
-            .expect("No socket address found");
+    // Runs without a seed get a random one, so they can be replayed too
+    let seed = *settings.seed.get_or_insert_with(rand::random);
+    println!("seed: {}", seed);
 
-        addrs = Some(Address::TCP(server));
-    }
+    let count = settings.count;
+    let cpus = settings.threads();
 
-    // Cant be none because it would have panicked earlier
-    let addrs = addrs.unwrap();
+    println!("cpus: {}", cpus);
 
-    let count: u32 = arg2
-        .parse()
-        .unwrap_or_else(|_| panic!("{} is not a number", arg2));
-    let mut cpus = 1.max(num_cpus::get()) as u32;
+    let settings = Arc::new(settings);
+    let hub = Arc::new(MetricsHub::new(cpus));
 
-    if let Some(str) = arg3 {
-        cpus = str
-            .parse()
-            .unwrap_or_else(|_| panic!("{} is not a number", arg2));
+    if let Some(metrics_address) = settings.metrics_address {
+        if let Err(err) = prometheus::start(metrics_address, hub.clone()) {
+            eprintln!(
+                "error: could not serve metrics on {}: {}",
+                metrics_address, err
+            );
+            std::process::exit(2);
+        }
+    }
+    // The first signal stops the run gracefully, a second one exits right away
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, stop.clone())?;
+        signal_hook::flag::register(signal, stop.clone())?;
     }
-
-    println!("cpus: {}", cpus);
 
     let count_per_thread = count / cpus;
     let mut extra = count % cpus;
     let mut names_used = 0;
 
     if count > 0 {
+        let start = Instant::now();
         let mut threads = Vec::new();
-        for _ in 0..cpus {
+        for thread in 0..cpus as usize {
             let mut count = count_per_thread;
 
             if extra > 0 {
@@ -108,16 +151,115 @@ fn main() -> io::Result<()> {
             }
 
             let addrs = addrs.clone();
+            let settings = settings.clone();
+            let hub = hub.clone();
+            let stop = stop.clone();
             threads.push(std::thread::spawn(move || {
-                start_bots(count, addrs, names_used, cpus)
+                start_bots(
+                    count,
+                    addrs,
+                    names_used,
+                    cpus,
+                    settings,
+                    hub.clone(),
+                    stop,
+                    thread,
+                );
+                hub.finish(thread);
             }));
 
             names_used += count;
         }
 
+        let interval = Duration::from_secs(settings.report_interval);
+        let mut next_report = start + interval;
+        let mut previous = Metrics::default();
+        let mut recorder = Recorder::new();
+        let mut next_sample = 1;
+        let mut stopping = false;
+        let mut next_phase = 0;
+        while threads.iter().any(|thread| !thread.is_finished()) {
+            std::thread::sleep(Duration::from_millis(100));
+
+            if !settings.phases.is_empty() && !stopping {
+                let position = phases::position(
+                    &settings.phases,
+                    start.elapsed().as_secs_f64(),
+                    settings.joins_per_tick * 20.0,
+                    settings.leave_rate,
+                );
+                match position {
+                    Some(position) if next_phase <= position.phase => {
+                        // Steps take no time, they are printed along with the phase after them
+                        for index in next_phase..=position.phase {
+                            println!(
+                                "phase {}/{}: {}",
+                                index + 1,
+                                settings.phases.len(),
+                                settings.phases[index]
+                            );
+                        }
+                        next_phase = position.phase + 1;
+                    }
+                    Some(_) => {}
+                    None => {
+                        stopping = true;
+                        println!(
+                            "phases finished, disconnecting the remaining bots at {} bots/s",
+                            settings.leave_rate
+                        );
+                    }
+                }
+            }
+
+            if !stopping && stop.load(Ordering::Relaxed) {
+                stopping = true;
+                println!(
+                    "stopping, disconnecting {} bots at {} bots/s, press ctrl-c again to exit now",
+                    hub.snapshot().online(),
+                    settings.leave_rate
+                );
+            }
+
+            // Every thread has to have flushed the second, otherwise its counters would land
+            // in the next sample
+            if hub.second() >= next_sample && hub.flushed_until() >= next_sample {
+                recorder.sample(next_sample, hub.snapshot());
+                next_sample += 1;
+            }
+
+            if settings.report_interval > 0 && Instant::now() >= next_report {
+                let metrics = hub.snapshot();
+                print!(
+                    "{}",
+                    metrics::summary(start.elapsed(), &metrics, &previous, interval)
+                );
+                previous = metrics;
+                next_report += interval;
+            }
+        }
+
         for thread in threads {
             let _ = thread.join();
         }
+
+        // The workers flushed their counters once more when they stopped, the part of a second
+        // since the last full one goes into a last sample
+        let metrics = hub.snapshot();
+        while next_sample <= hub.second() + 1 {
+            recorder.sample(next_sample, metrics.clone());
+            next_sample += 1;
+        }
+
+        let elapsed = start.elapsed();
+        print!("{}", metrics::final_report(elapsed, &metrics));
+
+        if let Some(report_file) = &settings.report_file {
+            match recorder.write(report_file, &settings, elapsed, &metrics) {
+                Ok(()) => println!("report written to {}", report_file.display()),
+                Err(err) => eprintln!("could not write report {}: {}", report_file.display(), err),
+            }
+        }
     }
     Ok(())
 }
@@ -127,24 +269,235 @@ pub struct Compression {
     decompressor: Decompressor,
 }
 
+/// State shared by every bot on a worker thread
+pub struct Context {
+    pub compression: Compression,
+    pub settings: Arc<Settings>,
+    pub metrics: Metrics,
+    /// Session joins running on helper threads
+    pub joins: Joins,
+    pub protocol: &'static Protocol,
+    /// Chunks received by any of the thread's bots
+    pub world: World,
+}
+
 pub struct Bot {
     pub token: Token,
     pub stream: Stream,
     pub name: String,
+    pub uuid: u128,
     pub id: u32,
     pub entity_id: u32,
+    /// Entities the server sent to the bot
+    pub entities: Entities,
     pub compression_threshold: i32,
+    pub encryption: Option<Encryption>,
+    /// Waiting for the session server before sending the Encryption Response
+    pub pending_join: Option<PendingJoin>,
     pub state: ProtocolState,
     pub kicked: bool,
     pub teleported: bool,
     pub x: f64,
     pub y: f64,
     pub z: f64,
+    pub body: Body,
     pub buffering_buf: Buf,
     pub joined: bool,
+    pub spawned_at: Instant,
+    /// When the current connection was opened, a transfer opens a new one
+    pub connect_started: Instant,
+    pub login_started: Option<Instant>,
+    /// When the status ping was sent
+    pub ping_sent: Option<Instant>,
+    pub last_keep_alive: Option<Instant>,
+    pub last_ping: Option<Instant>,
+    /// Payload and send time of the ping probe waiting for its response
+    pub probe: Option<(u64, Instant)>,
+    pub disconnect_reason: Option<String>,
+    pub packets_in: Traffic,
+    pub packets_out: Traffic,
+    /// Chat messages received, overlay messages aside
+    pub chat_received: u64,
+    /// Address the server asked the bot to transfer to, handled once its packets are processed
+    pub transfer: Option<(String, u16)>,
+    /// Whether the current connection was opened by a transfer
+    pub transferred: bool,
+    /// Disconnected by the bot itself rather than by the server or an error
+    pub left: bool,
+    /// Reconnects in a row that didn't reach the play state
+    pub reconnects: u32,
+    pub cookies: HashMap<String, Vec<u8>>,
+    /// Chunks the bot has loaded, they are stored in `Context::world`
+    pub chunks: HashSet<ChunkPos>,
+    /// Taken out while one of its hooks runs, see `behaviour::run`
+    pub behaviour: Option<Box<dyn Behaviour>>,
+    /// Every random choice the bot makes comes from here, see `bot_rng`
+    pub rng: StdRng,
+}
+
+/// The random number generator of the bot with the run wide `index`, so a run with the same
+/// seed makes the same choices
+fn bot_rng(seed: u64, index: u32) -> StdRng {
+    StdRng::seed_from_u64(seed ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+}
+
+impl Bot {
+    /// Marks the bot for removal, only the first reason is kept
+    pub fn kick(&mut self, reason: impl Into<String>) {
+        if !self.kicked {
+            self.kicked = true;
+            self.disconnect_reason = Some(reason.into());
+        }
+    }
+
+    /// Closes the connection from the bot's side
+    pub fn leave(&mut self, reason: impl Into<String>) {
+        self.stream.shutdown();
+        self.left = true;
+        self.kick(reason);
+    }
+
+    /// Connects to the address the server transferred the bot to, keeping its identity,
+    /// cookies and traffic counters
+    pub fn follow_transfer(
+        &mut self,
+        registry: &Registry,
+        host: &str,
+        port: u16,
+    ) -> Result<(), Error> {
+        let address = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("no socket address found for {}:{}", host, port))?;
+
+        self.connect(registry, &Address::TCP(address))?;
+        self.state = ProtocolState::Login;
+        self.transferred = true;
+        Ok(())
+    }
+
+    /// Opens a new connection and starts over, the caller sets the protocol state
+    pub fn connect(&mut self, registry: &Registry, address: &Address) -> io::Result<()> {
+        let _ = registry.deregister(&mut self.stream);
+        self.stream = Stream::Closed;
+        self.connect_started = Instant::now();
+        self.stream = address.connect()?;
+        registry.register(
+            &mut self.stream,
+            self.token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+
+        self.compression_threshold = 0;
+        self.encryption = None;
+        self.pending_join = None;
+        self.kicked = false;
+        self.teleported = false;
+        self.body = Body::default();
+        self.entities = Entities::default();
+        self.joined = false;
+        self.buffering_buf.set_reader_index(0);
+        self.buffering_buf.set_writer_index(0);
+        self.login_started = None;
+        self.last_keep_alive = None;
+        self.last_ping = None;
+        self.probe = None;
+        self.disconnect_reason = None;
+        self.transfer = None;
+        self.transferred = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Bot {
+    /// A bot in `state` connected to the returned socket, for running packet handlers by hand
+    pub fn paired(state: ProtocolState) -> (Bot, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.set_nonblocking(true).unwrap();
+        let bot = Bot {
+            token: Token(0),
+            stream: Stream::TCP(TcpStream::from_std(client)),
+            name: "Bot_0".to_owned(),
+            uuid: 0,
+            id: 0,
+            entity_id: 0,
+            entities: Entities::default(),
+            compression_threshold: 0,
+            encryption: None,
+            pending_join: None,
+            state,
+            kicked: false,
+            teleported: false,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            body: Body::default(),
+            buffering_buf: Buf::with_length(200),
+            joined: true,
+            spawned_at: Instant::now(),
+            connect_started: Instant::now(),
+            login_started: None,
+            ping_sent: None,
+            last_keep_alive: None,
+            last_ping: None,
+            probe: None,
+            disconnect_reason: None,
+            packets_in: Traffic::default(),
+            packets_out: Traffic::default(),
+            chat_received: 0,
+            transfer: None,
+            transferred: false,
+            left: false,
+            reconnects: 0,
+            cookies: HashMap::new(),
+            chunks: HashSet::new(),
+            behaviour: None,
+            rng: bot_rng(0, 0),
+        };
+        (bot, peer)
+    }
+
+    /// Reads the next uncompressed and unencrypted packet the bot sent to `peer`, from its id on
+    pub fn sent(peer: &mut std::net::TcpStream) -> Buf {
+        let mut length = 0;
+        for i in 0..3 {
+            let mut byte = [0u8];
+            peer.read_exact(&mut byte).unwrap();
+            length |= ((byte[0] & 0x7F) as usize) << (7 * i);
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut packet = vec![0u8; length];
+        peer.read_exact(&mut packet).unwrap();
+        let mut buf = Buf::new();
+        buf.write_bytes(&packet);
+        buf
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[cfg(test)]
+impl Context {
+    pub fn for_protocol(protocol: &'static Protocol, settings: Settings) -> Context {
+        Context {
+            compression: Compression {
+                compressor: Compressor::new(CompressionLvl::default()),
+                decompressor: Decompressor::new(),
+            },
+            joins: Joins::new(login::authenticator(&settings)),
+            protocol,
+            settings: Arc::new(settings),
+            metrics: Metrics::default(),
+            world: World::new(protocol.air_states, protocol.non_colliding),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ProtocolState {
     Status,
     Login,
@@ -152,184 +505,332 @@ pub enum ProtocolState {
     Play,
 }
 
-pub fn start_bots(count: u32, addrs: Address, name_offset: u32, cpus: u32) {
+impl ProtocolState {
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn start_bots(
+    count: u32,
+    addrs: Address,
+    name_offset: u32,
+    cpus: u32,
+    settings: Arc<Settings>,
+    hub: Arc<MetricsHub>,
+    stop: Arc<AtomicBool>,
+    thread: usize,
+) {
     if count == 0 {
         return;
     }
     let mut poll = Poll::new().expect("could not unwrap poll");
-    //todo check used cap
-    let mut events = Events::with_capacity((count * 5) as usize);
     let mut map = HashMap::new();
 
     println!("{:?}", addrs);
 
-    fn start_bot(bot: &mut Bot, compression: &mut Compression) {
+    fn start_bot(bot: &mut Bot, ctx: &mut Context) {
         bot.joined = true;
 
         // socket ops
         bot.stream.set_ops();
+        ctx.metrics
+            .connect_time
+            .record_duration(bot.connect_started.elapsed());
+
+        if ctx.settings.mode == Mode::Status {
+            let buf =
+                login::write_handshake_packet(ctx.settings.protocol_version, "".to_string(), 0, 1);
+            bot.send_packet(buf, ctx);
+            bot.send_packet(status::write_status_request(), ctx);
+            return;
+        }
 
         //login sequence
-        let buf = login::write_handshake_packet(PROTOCOL_VERSION, "".to_string(), 0, 2);
-        bot.send_packet(buf, compression);
+        let intent = if bot.transferred { 3 } else { 2 };
+        let buf =
+            login::write_handshake_packet(ctx.settings.protocol_version, "".to_string(), 0, intent);
+        bot.send_packet(buf, ctx);
 
-        let uuid: u128 = Uuid::new_v4().as_u128();
-        let buf = login::write_login_start_packet(&bot.name, uuid);
-        bot.send_packet(buf, compression);
+        let buf = login::write_login_start_packet(ctx.protocol, &bot.name, bot.uuid);
+        bot.send_packet(buf, ctx);
+        bot.login_started = Some(Instant::now());
 
         println!("bot \"{}\" joined", bot.name);
     }
 
-    let bots_per_tick = AVG_JOINS_PER_TICK / cpus as f64;
+    let bots_per_tick = match settings.mode {
+        Mode::Join => settings.joins_per_tick,
+        Mode::Status => settings.status_rate / 20.0,
+    } / cpus as f64;
+    // Status connections close after one round trip, so about as many are open at once as are
+    // opened within the connect timeout. Events beyond the capacity wait for the next poll.
+    let concurrent = match settings.mode {
+        Mode::Join => count,
+        Mode::Status => {
+            ((bots_per_tick * 20.0 * settings.connect_timeout).ceil() as u32).clamp(1, count)
+        }
+    };
+    let mut events = Events::with_capacity((concurrent * 5) as usize);
+    let initial_state = match settings.mode {
+        Mode::Join => ProtocolState::Login,
+        Mode::Status => ProtocolState::Status,
+    };
     let mut bots_this_tick = 0.0;
     let mut bots_joined = 0;
+    let mut bots_leaving = 0.0;
+    // With phases the ids of departed bots are handed out again, lowest first, so names stay
+    // within `count`
+    let phased = !settings.phases.is_empty();
+    let seed = settings.seed.unwrap_or_default();
+    let mut free_ids = BTreeSet::new();
+    // Bots that lost their connection this tick, and those waiting to reconnect
+    let mut gone = Vec::new();
+    let mut waiting: Vec<(Instant, Bot)> = Vec::new();
 
     let mut packet_buf = Buf::with_length(2000);
     let mut uncompressed_buf = Buf::with_length(2000);
 
-    let mut compression = Compression {
-        compressor: Compressor::new(CompressionLvl::default()),
-        decompressor: Decompressor::new(),
+    let protocol = protocol::get(settings.protocol_version).expect("unsupported protocol version");
+    let mut ctx = Context {
+        compression: Compression {
+            compressor: Compressor::new(CompressionLvl::default()),
+            decompressor: Decompressor::new(),
+        },
+        joins: Joins::new(login::authenticator(&settings)),
+        protocol,
+        settings,
+        metrics: Metrics::default(),
+        world: World::new(protocol.air_states, protocol.non_colliding),
     };
 
     let dur = Duration::from_millis(50);
+    let connect_timeout = Duration::from_secs_f64(ctx.settings.connect_timeout);
 
     let mut tick_counter = 0;
-    let action_tick = 4;
+    let mut flushed_second = 0;
+    let probe_ticks = if ctx.settings.ping_probe_interval > 0.0 {
+        ((ctx.settings.ping_probe_interval * 20.0).round() as u32).max(1)
+    } else {
+        0
+    };
 
-    'main: loop {
+    let chat_probe_ticks = if ctx.settings.chat_probe_interval > 0.0 {
+        ((ctx.settings.chat_probe_interval * 20.0).round() as u32).max(1)
+    } else {
+        0
+    };
+
+    let started = Instant::now();
+    loop {
         let ins = Instant::now();
 
-        if bots_joined < count {
-            bots_this_tick += bots_per_tick;
+        let position = phases::position(
+            &ctx.settings.phases,
+            started.elapsed().as_secs_f64(),
+            ctx.settings.joins_per_tick * 20.0,
+            ctx.settings.leave_rate,
+        );
+        let stopped = stop.load(Ordering::Relaxed);
+        let stopping = stopped || (phased && position.is_none());
+        let target = position.map(|position| phases::share(position.target, cpus, thread));
+
+        if stopping {
+            waiting.clear();
+        } else if !waiting.is_empty() {
+            let now = Instant::now();
+            let (due, later) = waiting.drain(..).partition(|(at, _)| *at <= now);
+            waiting = later;
+            for (_, mut bot) in due {
+                bot.cookies.clear();
+                println!("reconnect bot \"{}\" attempt {}", bot.name, bot.reconnects);
+                ctx.metrics.reconnects += 1;
+                match bot.connect(poll.registry(), &addrs) {
+                    Ok(()) => {
+                        bot.state = initial_state;
+                        map.insert(bot.token, bot);
+                    }
+                    Err(err) => {
+                        ctx.metrics.connect_failures += 1;
+                        bot.kick(format!("could not connect: {}", err));
+                        gone.push(bot);
+                    }
+                }
+            }
+        }
+
+        let (joins_per_tick, wanted) = match (position, target) {
+            (Some(position), Some(target)) => (
+                position.join_rate / 20.0 / cpus as f64,
+                target.saturating_sub((map.len() + waiting.len()) as u32),
+            ),
+            _ if phased => (0.0, 0),
+            _ => (bots_per_tick, count - bots_joined),
+        };
+
+        if wanted > 0 && !stopping {
+            bots_this_tick += joins_per_tick;
 
             let registry = poll.registry();
-            for bot in bots_joined..(bots_this_tick as u32 + bots_joined).min(count) {
+            let joining = (bots_this_tick as u32).min(wanted);
+            for _ in 0..joining {
+                let bot = free_ids.pop_first().unwrap_or(bots_joined);
                 let token = Token(bot as usize);
                 let name = "Bot_".to_owned() + &(name_offset + bot).to_string();
+                let mut rng = bot_rng(seed, name_offset + bot);
+                let uuid = uuid::Builder::from_random_bytes(rng.gen()).into_uuid();
+                let behaviour = behaviour::for_bot(&ctx.settings, name_offset + bot, &mut rng);
 
                 let mut bot = Bot {
                     token,
-                    stream: addrs.connect(),
+                    stream: Stream::Closed,
                     name,
+                    uuid: uuid.as_u128(),
                     id: bot,
                     entity_id: 0,
+                    entities: Entities::default(),
                     compression_threshold: 0,
-                    state: ProtocolState::Login,
+                    encryption: None,
+                    pending_join: None,
+                    state: initial_state,
                     kicked: false,
                     teleported: false,
                     x: 0.0,
                     y: 0.0,
                     z: 0.0,
+                    body: Body::default(),
                     buffering_buf: Buf::with_length(200),
                     joined: false,
+                    spawned_at: Instant::now(),
+                    connect_started: Instant::now(),
+                    login_started: None,
+                    ping_sent: None,
+                    last_keep_alive: None,
+                    last_ping: None,
+                    probe: None,
+                    disconnect_reason: None,
+                    packets_in: Traffic::default(),
+                    packets_out: Traffic::default(),
+                    chat_received: 0,
+                    transfer: None,
+                    transferred: false,
+                    left: false,
+                    reconnects: 0,
+                    cookies: HashMap::new(),
+                    chunks: HashSet::new(),
+                    behaviour: Some(behaviour),
+                    rng,
                 };
-                registry
-                    .register(
-                        &mut bot.stream,
-                        bot.token,
-                        Interest::READABLE | Interest::WRITABLE,
-                    )
-                    .expect("could not register");
-
-                println!("spawn bot \"{}\" {}/{}", bot.name, bots_joined, count);
+                if initial_state != ProtocolState::Status {
+                    println!("spawn bot \"{}\" {}/{}", bot.name, bot.id, count);
+                }
 
-                map.insert(token, bot);
+                if bot.id == bots_joined {
+                    bots_joined += 1;
+                }
+                ctx.metrics.spawned += 1;
+                match bot.connect(registry, &addrs) {
+                    Ok(()) => {
+                        map.insert(token, bot);
+                    }
+                    Err(err) => {
+                        ctx.metrics.connect_failures += 1;
+                        bot.kick(format!("could not connect: {}", err));
+                        gone.push(bot);
+                    }
+                }
 
-                bots_joined += 1;
                 bots_this_tick -= 1.0;
             }
+        } else {
+            // Credit isn't saved up while the thread is on target
+            bots_this_tick = f64::min(bots_this_tick, 1.0);
         }
 
-        poll.poll(&mut events, Some(dur)).expect("couldn't poll");
+        // Time spent waiting for events isn't counted as work
+        let mut busy = ins.elapsed();
+        if let Err(err) = poll.poll(&mut events, Some(dur)) {
+            // The stop signals interrupt the wait, the tick goes on without events
+            if err.kind() != io::ErrorKind::Interrupted {
+                panic!("couldn't poll: {}", err);
+            }
+        }
+        let processing = Instant::now();
         for event in events.iter() {
             if let Some(bot) = map.get_mut(&event.token()) {
                 if event.is_writable() && !bot.joined {
-                    start_bot(bot, &mut compression);
+                    // A failed non-blocking connect also shows up as writable
+                    match bot.stream.connected() {
+                        Ok(true) => start_bot(bot, &mut ctx),
+                        Ok(false) => {}
+                        Err(err) => {
+                            ctx.metrics.connect_failures += 1;
+                            bot.kick(format!("could not connect: {}", err));
+                        }
+                    }
                 }
                 if event.is_readable() && bot.joined {
-                    net::process_packet(
-                        bot,
-                        &mut packet_buf,
-                        &mut uncompressed_buf,
-                        &mut compression,
-                    );
+                    net::process_packet(bot, &mut packet_buf, &mut uncompressed_buf, &mut ctx);
+                    if let Some((host, port)) = bot.transfer.take() {
+                        ctx.world.unload_all(&mut bot.chunks);
+                        match bot.follow_transfer(poll.registry(), &host, port) {
+                            Ok(()) => ctx.metrics.transfers += 1,
+                            Err(err) => bot.kick(format!("transfer failed: {}", err)),
+                        }
+                    }
                     if bot.kicked {
-                        println!("{} disconnected", bot.name);
                         let token = bot.token;
-                        map.remove(&token).expect("kicked bot doesn't exist");
-
-                        if map.is_empty() {
-                            break 'main;
-                        }
+                        gone.push(map.remove(&token).expect("kicked bot doesn't exist"));
                     }
                 }
             }
         }
-
+        busy += processing.elapsed();
         let elapsed = ins.elapsed();
         if elapsed < dur {
             std::thread::sleep(dur - elapsed);
         }
 
+        let updating = Instant::now();
         let mut to_remove = Vec::new();
 
+        for join in ctx.joins.finished() {
+            if let Some(bot) = map.get_mut(&join.token) {
+                login::finish_join(bot, &mut ctx, join);
+            }
+        }
+
         for bot in map.values_mut() {
-            if SHOULD_MOVE && bot.teleported {
-                bot.x += rand::random::<f64>() * 1.0 - 0.5;
-                bot.z += rand::random::<f64>() * 1.0 - 0.5;
-                bot.send_packet(play::write_current_pos(bot), &mut compression);
-
-                if (tick_counter + bot.id) % action_tick == 0 {
-                    match rand::thread_rng().gen_range(0..=4u8) {
-                        0 => {
-                            // Send chat
-                            bot.send_packet(
-                                play::write_chat_message(
-                                    MESSAGES.choose(&mut rand::thread_rng()).unwrap(),
-                                ),
-                                &mut compression,
-                            );
-                        }
-                        1 => {
-                            // Punch animation
-                            bot.send_packet(
-                                play::write_animation(rand::random()),
-                                &mut compression,
-                            );
-                        }
-                        2 => {
-                            // Sneak
-                            bot.send_packet(
-                                play::write_entity_action(
-                                    bot.entity_id,
-                                    if rand::random() { 1 } else { 0 },
-                                    0,
-                                ),
-                                &mut compression,
-                            );
-                        }
-                        3 => {
-                            // Sprint
-                            bot.send_packet(
-                                play::write_entity_action(
-                                    bot.entity_id,
-                                    if rand::random() { 3 } else { 4 },
-                                    0,
-                                ),
-                                &mut compression,
-                            );
-                        }
-                        4 => {
-                            // Held item
-                            bot.send_packet(
-                                play::write_held_slot(rand::thread_rng().gen_range(0..9)),
-                                &mut compression,
-                            );
-                        }
-                        _ => {}
-                    }
-                }
+            if !bot.joined && bot.connect_started.elapsed() >= connect_timeout {
+                ctx.metrics.connect_failures += 1;
+                bot.kick("connect timed out");
+            }
+
+            if bot.state == ProtocolState::Play {
+                behaviour::run(bot, |behaviour, bot| {
+                    behaviour.on_tick(bot, &mut ctx, tick_counter)
+                });
+            }
+
+            if probe_ticks > 0
+                && bot.state == ProtocolState::Play
+                && (tick_counter + bot.id) % probe_ticks == 0
+            {
+                let payload = tick_counter as u64;
+                bot.probe = Some((payload, Instant::now()));
+                bot.send_packet(play::write_ping_request(ctx.protocol, payload), &mut ctx);
+            }
+
+            if chat_probe_ticks > 0
+                && bot.state == ProtocolState::Play
+                && name_offset + bot.id < ctx.settings.chat_probe_senders
+                && (tick_counter + bot.id) % chat_probe_ticks == 0
+            {
+                let message = chat::probe_message(&bot.name, tick_counter / chat_probe_ticks);
+                ctx.metrics.chat_sent += 1;
+                ctx.metrics.chat_probes_sent += 1;
+                bot.send_packet(play::write_chat_message(ctx.protocol, &message), &mut ctx);
             }
 
             if bot.kicked {
@@ -338,11 +839,142 @@ pub fn start_bots(count: u32, addrs: Address, name_offset: u32, cpus: u32) {
         }
 
         for bot in to_remove {
-            let _ = map.remove(&bot);
+            gone.extend(map.remove(&bot));
+        }
+
+        busy += updating.elapsed();
+        ctx.metrics.tick_duration.record_duration(busy);
+
+        let (leaves_per_tick, surplus) = match (position, target) {
+            _ if stopping => (ctx.settings.leave_rate / 20.0 / cpus as f64, map.len()),
+            (Some(position), Some(target)) => (
+                position.leave_rate / 20.0 / cpus as f64,
+                (map.len() + waiting.len()).saturating_sub(target as usize),
+            ),
+            _ => (0.0, 0),
+        };
+
+        if surplus > 0 {
+            bots_leaving += leaves_per_tick;
+            let reason = match position {
+                _ if stopped => "run stopped".to_owned(),
+                Some(position) if !stopping => format!("left in phase {}", position.phase + 1),
+                _ => "run finished".to_owned(),
+            };
+            let mut leaving = (bots_leaving as usize).min(surplus);
+            bots_leaving -= leaving as f64;
+            // The bots with the highest ids leave first, so the same seed makes the same bots
+            // leave and reuses the same ids. Bots waiting to reconnect are already gone, they
+            // just don't come back.
+            waiting.sort_by_key(|(_, bot)| bot.id);
+            while leaving > 0 {
+                match waiting.pop() {
+                    Some((_, bot)) => free_ids.insert(bot.id),
+                    None => break,
+                };
+                leaving -= 1;
+            }
+            let mut tokens: Vec<Token> = map.keys().copied().collect();
+            tokens.sort_unstable();
+            for token in tokens.into_iter().rev().take(leaving) {
+                if let Some(mut bot) = map.remove(&token) {
+                    bot.leave(reason.as_str());
+                    gone.push(bot);
+                }
+            }
+        } else {
+            bots_leaving = f64::min(bots_leaving, 1.0);
+        }
+
+        for mut bot in gone.drain(..) {
+            disconnected(&bot, &mut ctx.metrics);
+            ctx.world.unload_all(&mut bot.chunks);
+            if bot.state == ProtocolState::Play {
+                bot.reconnects = 0;
+            }
+            let reason = bot.disconnect_reason.as_deref().unwrap_or("unknown");
+            // Connections that never opened are retried even without `reconnect`
+            let retry = ctx.settings.reconnect || !bot.joined;
+            let delay = if bot.left || stopping || !retry {
+                None
+            } else {
+                reconnect::delay(&ctx.settings, reason, bot.reconnects + 1, &mut bot.rng)
+            };
+            match delay {
+                Some(delay) => {
+                    bot.reconnects += 1;
+                    println!("{} reconnecting in {:.1}s", bot.name, delay.as_secs_f64());
+                    waiting.push((Instant::now() + delay, bot));
+                }
+                None if phased => {
+                    free_ids.insert(bot.id);
+                }
+                None => {}
+            }
+        }
+
+        if map.is_empty() && waiting.is_empty() && (stopping || (!phased && bots_joined == count)) {
+            break;
+        }
+
+        // Publish the metrics to the hub once a second, on the same seconds on every thread
+        let second = hub.second();
+        if second > flushed_second {
+            flush_metrics(&map, &mut ctx.metrics, &hub, thread, second);
+            flushed_second = second;
         }
 
         tick_counter += 1;
     }
+
+    flush_metrics(&map, &mut ctx.metrics, &hub, thread, hub.second());
+}
+
+fn flush_metrics(
+    map: &HashMap<Token, Bot>,
+    metrics: &mut Metrics,
+    hub: &MetricsHub,
+    thread: usize,
+    second: u64,
+) {
+    metrics.online = [0; 4];
+    metrics.entities = 0;
+    for bot in map.values() {
+        if bot.joined {
+            metrics.online[bot.state.index()] += 1;
+        }
+        if bot.state == ProtocolState::Play {
+            metrics.entities += bot.entities.len() as u64;
+            metrics.tracked_entities.record(bot.entities.len() as u64);
+        }
+    }
+    hub.flush(thread, second, metrics);
+}
+
+fn disconnected(bot: &Bot, metrics: &mut Metrics) {
+    let reason = bot.disconnect_reason.as_deref().unwrap_or("unknown");
+    if !bot.left && !matches!(bot.state, ProtocolState::Play | ProtocolState::Status) {
+        metrics.join_failures += 1;
+    }
+    if bot.state == ProtocolState::Play {
+        metrics.chat_per_bot.record(bot.chat_received);
+    }
+    metrics.record_disconnect(reason);
+    // Status connections close after every ping, only the failed ones are worth a line
+    if bot.state == ProtocolState::Status && reason == status::COMPLETE {
+        return;
+    }
+    println!(
+        "{} disconnected after {:.1}s ({}), in: {} packets / {} bytes, out: {} packets / {} bytes, chat received: {}",
+        bot.name,
+        bot.spawned_at.elapsed().as_secs_f64(),
+        reason,
+        bot.packets_in.packets,
+        bot.packets_in.bytes,
+        bot.packets_out.packets,
+        bot.packets_out.bytes,
+        bot.chat_received,
+    );
 }
 
 #[derive(Clone, Debug)]
@@ -353,15 +985,12 @@ pub enum Address {
 }
 
 impl Address {
-    pub fn connect(&self) -> Stream {
+    /// Starts a non-blocking connect, TCP errors mostly show up once the stream is writable
+    pub fn connect(&self) -> io::Result<Stream> {
         match self {
             #[cfg(unix)]
-            Address::UNIX(path) => {
-                Stream::UNIX(UnixStream::connect(path).expect("Could not connect to the server"))
-            }
-            Address::TCP(address) => Stream::TCP(
-                TcpStream::connect(address.to_owned()).expect("Could not connect to the server"),
-            ),
+            Address::UNIX(path) => Ok(Stream::UNIX(UnixStream::connect(path)?)),
+            Address::TCP(address) => Ok(Stream::TCP(TcpStream::connect(*address)?)),
         }
     }
 }
@@ -370,15 +999,50 @@ pub enum Stream {
     #[cfg(unix)]
     UNIX(UnixStream),
     TCP(TcpStream),
+    /// No connection, before the first connect or after one failed
+    Closed,
+}
+
+fn not_connected<T>() -> io::Result<T> {
+    Err(io::ErrorKind::NotConnected.into())
 }
 
 impl Stream {
-    pub fn set_ops(&mut self) {
+    pub fn shutdown(&mut self) {
+        let _ = match self {
+            #[cfg(unix)]
+            Stream::UNIX(s) => s.shutdown(Shutdown::Both),
+            Stream::TCP(s) => s.shutdown(Shutdown::Both),
+            Stream::Closed => Ok(()),
+        };
+    }
+
+    /// Whether the connect has finished, or why it failed
+    pub fn connected(&mut self) -> io::Result<bool> {
         match self {
+            #[cfg(unix)]
+            Stream::UNIX(s) => match s.take_error()? {
+                Some(err) => Err(err),
+                None => Ok(true),
+            },
             Stream::TCP(s) => {
-                s.set_nodelay(true).unwrap();
+                if let Some(err) = s.take_error()? {
+                    return Err(err);
+                }
+                match s.peer_addr() {
+                    Ok(_) => Ok(true),
+                    Err(err) if err.kind() == io::ErrorKind::NotConnected => Ok(false),
+                    Err(err) => Err(err),
+                }
             }
-            _ => {}
+            Stream::Closed => not_connected(),
+        }
+    }
+
+    pub fn set_ops(&mut self) {
+        #[allow(irrefutable_let_patterns)]
+        if let Stream::TCP(s) = self {
+            s.set_nodelay(true).unwrap();
         }
     }
 }
@@ -389,6 +1053,7 @@ impl Read for Stream {
             #[cfg(unix)]
             Stream::UNIX(s) => s.read(buf),
             Stream::TCP(s) => s.read(buf),
+            Stream::Closed => not_connected(),
         }
     }
 }
@@ -399,6 +1064,7 @@ impl Write for Stream {
             #[cfg(unix)]
             Stream::UNIX(s) => s.write(buf),
             Stream::TCP(s) => s.write(buf),
+            Stream::Closed => not_connected(),
         }
     }
 
@@ -407,6 +1073,7 @@ impl Write for Stream {
             #[cfg(unix)]
             Stream::UNIX(s) => s.flush(),
             Stream::TCP(s) => s.flush(),
+            Stream::Closed => Ok(()),
         }
     }
 }
@@ -422,6 +1089,7 @@ impl event::Source for Stream {
             #[cfg(unix)]
             Stream::UNIX(s) => s.register(registry, token, interests),
             Stream::TCP(s) => s.register(registry, token, interests),
+            Stream::Closed => not_connected(),
         }
     }
 
@@ -435,6 +1103,7 @@ impl event::Source for Stream {
             #[cfg(unix)]
             Stream::UNIX(s) => s.reregister(registry, token, interests),
             Stream::TCP(s) => s.reregister(registry, token, interests),
+            Stream::Closed => not_connected(),
         }
     }
 
@@ -443,6 +1112,115 @@ impl event::Source for Stream {
             #[cfg(unix)]
             Stream::UNIX(s) => s.deregister(registry),
             Stream::TCP(s) => s.deregister(registry),
+            Stream::Closed => Ok(()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::ActionRate;
+
+    /// Everything the bot with `index` sends in 200 ticks of the random behaviour
+    fn random_run(seed: u64, index: u32) -> Vec<u8> {
+        let mut settings = Settings {
+            action_tick: 1,
+            ..Settings::default()
+        };
+        settings.actions.random_phase = true;
+        settings.actions.movement = ActionRate::default();
+        let protocol = protocol::get(settings.protocol_version).unwrap();
+        let mut ctx = Context::for_protocol(protocol, settings);
+        let (mut bot, mut peer) = Bot::paired(ProtocolState::Play);
+        bot.rng = bot_rng(seed, index);
+        bot.teleported = true;
+        let mut behaviour = behaviour::for_bot(&ctx.settings.clone(), index, &mut bot.rng);
+        for tick in 0..200 {
+            behaviour.on_tick(&mut bot, &mut ctx, tick);
+        }
+
+        let mut sent = Vec::new();
+        peer.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let _ = peer.read_to_end(&mut sent);
+        assert!(!sent.is_empty());
+        sent
+    }
+
+    #[test]
+    fn seeded_bots_repeat_themselves() {
+        assert_eq!(random_run(1, 3), random_run(1, 3));
+        assert_ne!(random_run(1, 3), random_run(2, 3));
+        assert_ne!(random_run(1, 3), random_run(1, 4));
+    }
+
+    /// Waits for the connect of a new stream to `address` to finish
+    fn connect(address: &Address) -> io::Result<bool> {
+        let mut poll = Poll::new()?;
+        let mut events = Events::with_capacity(1);
+        let mut stream = address.connect()?;
+        poll.registry()
+            .register(&mut stream, Token(0), Interest::WRITABLE)?;
+        poll.poll(&mut events, Some(Duration::from_secs(5)))?;
+        stream.connected()
+    }
+
+    #[test]
+    fn connect_failures_are_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        assert!(connect(&Address::TCP(open)).unwrap());
+
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        assert!(connect(&Address::TCP(closed)).is_err());
+        #[cfg(unix)]
+        assert!(connect(&Address::UNIX(PathBuf::from("/nonexistent/mc.sock"))).is_err());
+        assert!(Stream::Closed.connected().is_err());
+    }
+
+    #[test]
+    fn stopping_disconnects_every_bot() {
+        // Accepts the connections but never answers, so the bots wait in the login state
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let settings = Settings {
+            server: Some(address.to_string()),
+            count: 3,
+            leave_rate: 40.0,
+            ..Settings::default()
+        };
+        let hub = Arc::new(MetricsHub::new(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopper = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(500));
+                stop.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let started = Instant::now();
+        let settings = Arc::new(settings);
+        start_bots(
+            3,
+            Address::TCP(address),
+            0,
+            1,
+            settings,
+            hub.clone(),
+            stop,
+            0,
+        );
+        stopper.join().unwrap();
+        // Two bots leave per tick
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let metrics = hub.snapshot();
+        assert_eq!((metrics.spawned, metrics.disconnected), (3, 3));
+        assert_eq!(metrics.disconnect_reasons["run stopped"], 3);
+        assert_eq!(metrics.online(), 0);
+    }
+}
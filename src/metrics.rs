@@ -0,0 +1,788 @@
+use crate::ProtocolState;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Distinct reasons kept per map, the rest are counted under `OTHER_REASONS`, so reasons with
+/// names, coordinates or timestamps in them don't grow the maps without bound
+const MAX_REASONS: usize = 100;
+const MAX_REASON_CHARS: usize = 200;
+pub const OTHER_REASONS: &str = "(other reasons)";
+
+const LINEAR_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = 3;
+const BUCKETS: usize = LINEAR_BUCKETS as usize + (64 - 4) * (1 << SUB_BUCKET_BITS);
+
+/// Log-linear histogram, of microsecond values unless noted otherwise, accurate to within 12.5%
+#[derive(Clone)]
+pub struct Histogram {
+    buckets: Box<[u64; BUCKETS]>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: Box::new([0; BUCKETS]),
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+
+    fn bucket(value: u64) -> usize {
+        if value < LINEAR_BUCKETS {
+            return value as usize;
+        }
+        let exponent = 63 - value.leading_zeros();
+        let sub = (value >> (exponent - SUB_BUCKET_BITS)) & ((1 << SUB_BUCKET_BITS) - 1);
+        LINEAR_BUCKETS as usize + ((exponent - 4) << SUB_BUCKET_BITS) as usize + sub as usize
+    }
+
+    /// Upper bound of the values that land in `bucket`
+    fn bucket_value(bucket: usize) -> u64 {
+        if bucket < LINEAR_BUCKETS as usize {
+            return bucket as u64;
+        }
+        let bucket = bucket - LINEAR_BUCKETS as usize;
+        let exponent = (bucket >> SUB_BUCKET_BITS) as u32 + 4;
+        let sub = (bucket & ((1 << SUB_BUCKET_BITS) - 1)) as u64;
+        let low = (1u64 << exponent) | (sub << (exponent - SUB_BUCKET_BITS));
+        low + ((1u64 << (exponent - SUB_BUCKET_BITS)) - 1)
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.buckets[Self::bucket(value)] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.max = self.max.max(value);
+    }
+
+    pub fn record_duration(&mut self, duration: Duration) {
+        self.record(duration.as_micros() as u64);
+    }
+
+    /// Records the time since the previous event, if any, and remembers this one
+    pub fn record_interval(&mut self, previous: &mut Option<Instant>) {
+        let now = Instant::now();
+        if let Some(previous) = previous.replace(now) {
+            self.record_duration(now - previous);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.sum.checked_div(self.count).unwrap_or(0)
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// The lowest value above the bucket `value` falls in, so `count_at_most(edge - 1)` is exact
+    pub fn bucket_edge(value: u64) -> u64 {
+        Self::bucket_value(Self::bucket(value)).saturating_add(1)
+    }
+
+    /// Number of recorded values that are at most `value`, exact at bucket boundaries
+    pub fn count_at_most(&self, value: u64) -> u64 {
+        let last = Self::bucket(value);
+        let mut count: u64 = self.buckets[..last].iter().sum();
+        if Self::bucket_value(last) <= value {
+            count += self.buckets[last];
+        }
+        count
+    }
+
+    /// `quantile` is between 0 and 1
+    pub fn percentile(&self, quantile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::bucket_value(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Traffic {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Traffic {
+    pub fn add(&mut self, bytes: u64) {
+        self.packets += 1;
+        self.bytes += bytes;
+    }
+
+    pub fn merge(&mut self, other: &Traffic) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Sizes of the packets that went through zlib
+#[derive(Clone, Copy, Default)]
+pub struct Compressed {
+    pub packets: u64,
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl Compressed {
+    pub fn add(&mut self, uncompressed: u64, compressed: u64) {
+        self.packets += 1;
+        self.uncompressed_bytes += uncompressed;
+        self.compressed_bytes += compressed;
+    }
+
+    pub fn merge(&mut self, other: &Compressed) {
+        self.packets += other.packets;
+        self.uncompressed_bytes += other.uncompressed_bytes;
+        self.compressed_bytes += other.compressed_bytes;
+    }
+
+    pub fn saved(&self) -> u64 {
+        self.uncompressed_bytes
+            .saturating_sub(self.compressed_bytes)
+    }
+}
+
+/// Counters and histograms of one worker thread, or the sum of all of them
+#[derive(Clone, Default)]
+pub struct Metrics {
+    /// Bots currently connected in each protocol state, indexed by `ProtocolState::index`
+    pub online: [u32; 4],
+    /// Entities tracked by the bots in the play state, summed over the bots
+    pub entities: u64,
+
+    pub spawned: u64,
+    pub joined: u64,
+    /// Bots that disconnected before reaching the play state
+    pub join_failures: u64,
+    /// Connections that were refused, failed or timed out before opening
+    pub connect_failures: u64,
+    pub disconnected: u64,
+    pub keep_alives: u64,
+    /// Transfers to another server that were followed
+    pub transfers: u64,
+    /// Connections reopened by bots that were kicked or lost their connection
+    pub reconnects: u64,
+    /// Chat messages sent by the bots
+    pub chat_sent: u64,
+    /// Chat messages received by the bots, summed over the bots
+    pub chat_received: u64,
+    /// Chat messages sent by the latency probe, also counted in `chat_sent`
+    pub chat_probes_sent: u64,
+    /// Chat packets skipped because their text couldn't be read
+    pub chat_unreadable: u64,
+    /// Commands sent by the `commands` behaviour
+    pub commands_sent: u64,
+    /// System chat answering a command with an error
+    pub command_errors: u64,
+    /// Status responses that were valid JSON
+    pub status_ok: u64,
+    pub status_invalid: u64,
+
+    /// Time from opening the socket until it became writable
+    pub connect_time: Histogram,
+    /// Time from sending Login Start until the bot entered the play state
+    pub join_latency: Histogram,
+    /// Time a worker spent processing each tick, without waiting for events
+    pub tick_duration: Histogram,
+    /// Round trip of the status ping
+    pub ping_rtt: Histogram,
+    /// Time between two keep alives sent to the same bot, server lag shows up as jitter
+    pub keep_alive_interval: Histogram,
+    /// Time between two pings sent by the server to the same bot
+    pub server_ping_interval: Histogram,
+    /// Round trip of the ping probe sent in the play state
+    pub probe_rtt: Histogram,
+    /// Time from sending a chat probe until another bot received it
+    pub chat_latency: Histogram,
+    /// Entities tracked by each bot in the play state, sampled about once a second
+    pub tracked_entities: Histogram,
+    /// Chat messages each bot received over its connection, recorded when a bot in the play
+    /// state disconnects
+    pub chat_per_bot: Histogram,
+
+    pub compression_in: Compressed,
+    pub compression_out: Compressed,
+
+    pub packets_in: HashMap<(ProtocolState, u32), Traffic>,
+    pub packets_out: HashMap<(ProtocolState, u32), Traffic>,
+    pub disconnect_reasons: HashMap<String, u64>,
+    /// Messages of the Disconnect packets sent by the server
+    pub kick_reasons: HashMap<String, u64>,
+    /// Errors sent in answer to commands, by the `errors` pattern they matched
+    pub command_error_patterns: HashMap<String, u64>,
+}
+
+impl Metrics {
+    pub fn record_in(&mut self, state: ProtocolState, id: u32, bytes: u64) {
+        self.packets_in.entry((state, id)).or_default().add(bytes);
+    }
+
+    pub fn record_out(&mut self, state: ProtocolState, id: u32, bytes: u64) {
+        self.packets_out.entry((state, id)).or_default().add(bytes);
+    }
+
+    pub fn record_disconnect(&mut self, reason: &str) {
+        self.disconnected += 1;
+        count_reason(&mut self.disconnect_reasons, reason, 1);
+    }
+
+    pub fn record_kick(&mut self, reason: &str) {
+        count_reason(&mut self.kick_reasons, reason, 1);
+    }
+
+    pub fn record_command_error(&mut self, pattern: &str) {
+        self.command_errors += 1;
+        count_reason(&mut self.command_error_patterns, pattern, 1);
+    }
+
+    pub fn online(&self) -> u32 {
+        self.online.iter().sum()
+    }
+
+    pub fn total_in(&self) -> Traffic {
+        total(&self.packets_in)
+    }
+
+    pub fn total_out(&self) -> Traffic {
+        total(&self.packets_out)
+    }
+
+    /// Adds the counters of `other`, gauges are added as well so merging every thread sums them
+    pub fn merge(&mut self, other: &Metrics) {
+        for (online, other) in self.online.iter_mut().zip(other.online.iter()) {
+            *online += other;
+        }
+        self.entities += other.entities;
+        self.spawned += other.spawned;
+        self.joined += other.joined;
+        self.join_failures += other.join_failures;
+        self.connect_failures += other.connect_failures;
+        self.disconnected += other.disconnected;
+        self.keep_alives += other.keep_alives;
+        self.transfers += other.transfers;
+        self.reconnects += other.reconnects;
+        self.chat_sent += other.chat_sent;
+        self.chat_received += other.chat_received;
+        self.chat_probes_sent += other.chat_probes_sent;
+        self.chat_unreadable += other.chat_unreadable;
+        self.commands_sent += other.commands_sent;
+        self.command_errors += other.command_errors;
+        self.status_ok += other.status_ok;
+        self.status_invalid += other.status_invalid;
+        self.connect_time.merge(&other.connect_time);
+        self.join_latency.merge(&other.join_latency);
+        self.tick_duration.merge(&other.tick_duration);
+        self.ping_rtt.merge(&other.ping_rtt);
+        self.keep_alive_interval.merge(&other.keep_alive_interval);
+        self.server_ping_interval.merge(&other.server_ping_interval);
+        self.probe_rtt.merge(&other.probe_rtt);
+        self.chat_latency.merge(&other.chat_latency);
+        self.tracked_entities.merge(&other.tracked_entities);
+        self.chat_per_bot.merge(&other.chat_per_bot);
+        self.compression_in.merge(&other.compression_in);
+        self.compression_out.merge(&other.compression_out);
+        for (key, traffic) in &other.packets_in {
+            self.packets_in.entry(*key).or_default().merge(traffic);
+        }
+        for (key, traffic) in &other.packets_out {
+            self.packets_out.entry(*key).or_default().merge(traffic);
+        }
+        for (reason, count) in &other.disconnect_reasons {
+            count_reason(&mut self.disconnect_reasons, reason, *count);
+        }
+        for (reason, count) in &other.kick_reasons {
+            count_reason(&mut self.kick_reasons, reason, *count);
+        }
+        for (pattern, count) in &other.command_error_patterns {
+            count_reason(&mut self.command_error_patterns, pattern, *count);
+        }
+    }
+}
+
+/// Long reasons are cut short, and new ones go to `OTHER_REASONS` once the map is full
+fn count_reason(reasons: &mut HashMap<String, u64>, reason: &str, count: u64) {
+    let reason = match reason.char_indices().nth(MAX_REASON_CHARS) {
+        Some((end, _)) => format!("{}...", &reason[..end]),
+        None => reason.to_owned(),
+    };
+    let named = reasons.len() - reasons.contains_key(OTHER_REASONS) as usize;
+    if let Some(counted) = reasons.get_mut(&reason) {
+        *counted += count;
+    } else if named < MAX_REASONS && reason != OTHER_REASONS {
+        reasons.insert(reason, count);
+    } else {
+        *reasons.entry(OTHER_REASONS.to_owned()).or_default() += count;
+    }
+}
+
+fn total(traffic: &HashMap<(ProtocolState, u32), Traffic>) -> Traffic {
+    let mut total = Traffic::default();
+    for traffic in traffic.values() {
+        total.merge(traffic);
+    }
+    total
+}
+
+/// Where the worker threads publish their metrics.
+///
+/// Workers flush once per second of the run, so a snapshot taken once every worker has flushed
+/// a second holds exactly the counters up to the end of that second.
+pub struct MetricsHub {
+    started: Instant,
+    threads: Vec<Mutex<Metrics>>,
+    /// Second of the run every thread last flushed at, `u64::MAX` once it is done
+    flushed: Vec<AtomicU64>,
+}
+
+impl MetricsHub {
+    pub fn new(threads: u32) -> MetricsHub {
+        MetricsHub {
+            started: Instant::now(),
+            threads: (0..threads)
+                .map(|_| Mutex::new(Metrics::default()))
+                .collect(),
+            flushed: (0..threads).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Whole seconds since the hub was created, workers flush when it changes
+    pub fn second(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// The last second every thread has flushed
+    pub fn flushed_until(&self) -> u64 {
+        self.flushed
+            .iter()
+            .map(|second| second.load(Ordering::Acquire))
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// The thread won't flush anymore, samples no longer wait for it
+    pub fn finish(&self, thread: usize) {
+        self.flushed[thread].store(u64::MAX, Ordering::Release);
+    }
+
+    /// Moves the counters collected by a worker up to the end of `second` into the hub
+    pub fn flush(&self, thread: usize, second: u64, local: &mut Metrics) {
+        let mut shared = self.threads[thread].lock().unwrap();
+        let online = local.online;
+        let entities = local.entities;
+        // Gauges are absolute so they replace the previous value instead of adding to it
+        shared.online = [0; 4];
+        shared.entities = 0;
+        shared.merge(local);
+        *local = Metrics {
+            online,
+            entities,
+            ..Metrics::default()
+        };
+        drop(shared);
+        self.flushed[thread].fetch_max(second, Ordering::Release);
+    }
+
+    pub fn snapshot(&self) -> Metrics {
+        let mut metrics = Metrics::default();
+        for thread in &self.threads {
+            metrics.merge(&thread.lock().unwrap());
+        }
+        metrics
+    }
+}
+
+fn format_micros(micros: u64) -> String {
+    if micros >= 1_000_000 {
+        format!("{:.2}s", micros as f64 / 1_000_000.0)
+    } else if micros >= 1_000 {
+        format!("{:.1}ms", micros as f64 / 1_000.0)
+    } else {
+        format!("{}us", micros)
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    if bytes >= 1024.0 * 1024.0 {
+        format!("{:.2} MiB", bytes / (1024.0 * 1024.0))
+    } else if bytes >= 1024.0 {
+        format!("{:.1} KiB", bytes / 1024.0)
+    } else {
+        format!("{:.0} B", bytes)
+    }
+}
+
+fn latency_row(out: &mut String, name: &str, histogram: &Histogram) {
+    let _ = writeln!(
+        out,
+        "  {:<14}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}",
+        name,
+        histogram.count(),
+        format_micros(histogram.mean()),
+        format_micros(histogram.percentile(0.5)),
+        format_micros(histogram.percentile(0.9)),
+        format_micros(histogram.percentile(0.99)),
+        format_micros(histogram.max()),
+    );
+}
+
+/// Table printed every report interval, rates are computed against the previous snapshot
+pub fn summary(elapsed: Duration, now: &Metrics, previous: &Metrics, interval: Duration) -> String {
+    let seconds = interval.as_secs_f64().max(0.001);
+    let mut out = String::new();
+
+    let _ = writeln!(out, "--- {:.0}s ---", elapsed.as_secs_f64());
+    let _ = writeln!(
+        out,
+        "  {:<8}{:>8}{:>8}{:>8}{:>8}{:>10}{:>10}{:>14}",
+        "bots", "online", "login", "config", "play", "spawned", "joined", "disconnected"
+    );
+    let _ = writeln!(
+        out,
+        "  {:<8}{:>8}{:>8}{:>8}{:>8}{:>10}{:>10}{:>14}",
+        "",
+        now.online(),
+        now.online[ProtocolState::Login.index()],
+        now.online[ProtocolState::Config.index()],
+        now.online[ProtocolState::Play.index()],
+        now.spawned,
+        now.joined,
+        now.disconnected,
+    );
+
+    let _ = writeln!(
+        out,
+        "  {:<8}{:>12}{:>14}{:>14}{:>14}",
+        "traffic", "packets/s", "bytes/s", "packets", "bytes"
+    );
+    for (name, total, previous) in [
+        ("in", now.total_in(), previous.total_in()),
+        ("out", now.total_out(), previous.total_out()),
+    ] {
+        let _ = writeln!(
+            out,
+            "  {:<8}{:>12.0}{:>14}{:>14}{:>14}",
+            name,
+            (total.packets - previous.packets) as f64 / seconds,
+            format_bytes((total.bytes - previous.bytes) as f64 / seconds),
+            total.packets,
+            format_bytes(total.bytes as f64),
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "  {:<14}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}",
+        "latency", "count", "mean", "p50", "p90", "p99", "max"
+    );
+    latency_row(&mut out, "connect", &now.connect_time);
+    latency_row(&mut out, "login->play", &now.join_latency);
+
+    for (name, histogram) in [
+        ("keep alive gap", &now.keep_alive_interval),
+        ("ping gap", &now.server_ping_interval),
+        ("probe rtt", &now.probe_rtt),
+        ("chat latency", &now.chat_latency),
+    ] {
+        if histogram.count() > 0 {
+            latency_row(&mut out, name, histogram);
+        }
+    }
+
+    if now.chat_sent + now.chat_received > 0 {
+        let _ = writeln!(
+            out,
+            "  {:<8}{:>12}{:>14}{:>14}{:>14}",
+            "chat", "sent/s", "received/s", "sent", "received"
+        );
+        let _ = writeln!(
+            out,
+            "  {:<8}{:>12.1}{:>14.1}{:>14}{:>14}",
+            "",
+            (now.chat_sent - previous.chat_sent) as f64 / seconds,
+            (now.chat_received - previous.chat_received) as f64 / seconds,
+            now.chat_sent,
+            now.chat_received,
+        );
+    }
+
+    let tracked = &now.tracked_entities;
+    if tracked.count() > 0 {
+        let _ = writeln!(
+            out,
+            "  {:<14}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}",
+            "entities", "tracked", "mean/bot", "p50", "p90", "p99", "max"
+        );
+        let _ = writeln!(
+            out,
+            "  {:<14}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}",
+            "",
+            now.entities,
+            tracked.mean(),
+            tracked.percentile(0.5),
+            tracked.percentile(0.9),
+            tracked.percentile(0.99),
+            tracked.max(),
+        );
+    }
+
+    let responses = now.status_ok + now.status_invalid;
+    if responses > 0 {
+        latency_row(&mut out, "ping rtt", &now.ping_rtt);
+        let _ = writeln!(
+            out,
+            "  {:<8}{:>12}{:>10}{:>10}",
+            "status", "conn/s", "ok", "invalid"
+        );
+        let _ = writeln!(
+            out,
+            "  {:<8}{:>12.1}{:>10}{:>10}",
+            "",
+            (now.spawned - previous.spawned) as f64 / seconds,
+            now.status_ok,
+            now.status_invalid,
+        );
+    }
+
+    out
+}
+
+/// Summary followed by the per packet and disconnect breakdowns, printed once the run is over
+pub fn final_report(elapsed: Duration, metrics: &Metrics) -> String {
+    let mut out = summary(elapsed, metrics, &Metrics::default(), elapsed);
+
+    let _ = writeln!(out, "  connect failures: {}", metrics.connect_failures);
+    let _ = writeln!(out, "  keep alives answered: {}", metrics.keep_alives);
+    let _ = writeln!(out, "  transfers followed: {}", metrics.transfers);
+    let _ = writeln!(out, "  reconnects: {}", metrics.reconnects);
+    if metrics.chat_probes_sent > 0 {
+        let _ = writeln!(
+            out,
+            "  chat probes: {} sent, {} received by other bots",
+            metrics.chat_probes_sent,
+            metrics.chat_latency.count()
+        );
+    }
+    if metrics.chat_sent > 0 {
+        let _ = writeln!(
+            out,
+            "  chat fan-out: {:.1} messages received per message sent",
+            metrics.chat_received as f64 / metrics.chat_sent as f64
+        );
+    }
+    if metrics.chat_unreadable > 0 {
+        let _ = writeln!(
+            out,
+            "  unreadable chat skipped: {}",
+            metrics.chat_unreadable
+        );
+    }
+    if metrics.commands_sent > 0 {
+        let _ = writeln!(
+            out,
+            "  commands: {} sent, {} answered with an error",
+            metrics.commands_sent, metrics.command_errors
+        );
+    }
+    let per_bot = &metrics.chat_per_bot;
+    if per_bot.count() > 0 {
+        let _ = writeln!(
+            out,
+            "  chat received per bot: mean {}, p50 {}, p90 {}, p99 {}, max {}",
+            per_bot.mean(),
+            per_bot.percentile(0.5),
+            per_bot.percentile(0.9),
+            per_bot.percentile(0.99),
+            per_bot.max(),
+        );
+    }
+
+    for (name, traffic) in [("in", &metrics.packets_in), ("out", &metrics.packets_out)] {
+        let mut packets: Vec<_> = traffic.iter().collect();
+        packets.sort_by_key(|(_, traffic)| std::cmp::Reverse(traffic.bytes));
+
+        let _ = writeln!(
+            out,
+            "  {:<8}{:<8}{:>6}{:>12}{:>14}",
+            format!("{} by id", name),
+            "",
+            "id",
+            "packets",
+            "bytes"
+        );
+        for ((state, id), traffic) in packets {
+            let _ = writeln!(
+                out,
+                "  {:<16}  0x{:02X}{:>12}{:>14}",
+                format!("{:?}", state),
+                id,
+                traffic.packets,
+                format_bytes(traffic.bytes as f64),
+            );
+        }
+    }
+
+    if !metrics.disconnect_reasons.is_empty() {
+        let mut reasons: Vec<_> = metrics.disconnect_reasons.iter().collect();
+        reasons.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+
+        let _ = writeln!(out, "  {:>8}  disconnect reason", "count");
+        for (reason, count) in reasons {
+            let _ = writeln!(out, "  {:>8}  {}", count, reason);
+        }
+    }
+
+    if !metrics.command_error_patterns.is_empty() {
+        let mut messages: Vec<_> = metrics.command_error_patterns.iter().collect();
+        messages.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+
+        let _ = writeln!(out, "  {:>8}  command error", "count");
+        for (message, count) in messages {
+            let _ = writeln!(out, "  {:>8}  {}", count, message);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_are_exact() {
+        let mut histogram = Histogram::new();
+        for value in 1..=10 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count(), 10);
+        assert_eq!(histogram.sum(), 55);
+        assert_eq!(histogram.mean(), 5);
+        assert_eq!(histogram.max(), 10);
+        assert_eq!(histogram.percentile(0.0), 1);
+        assert_eq!(histogram.percentile(0.5), 5);
+        assert_eq!(histogram.percentile(0.9), 9);
+        assert_eq!(histogram.percentile(1.0), 10);
+        assert_eq!(histogram.count_at_most(0), 0);
+        assert_eq!(histogram.count_at_most(4), 4);
+        assert_eq!(histogram.count_at_most(100), 10);
+        assert_eq!(Histogram::new().percentile(0.5), 0);
+    }
+
+    #[test]
+    fn percentiles_are_within_the_bucket_accuracy() {
+        let mut histogram = Histogram::new();
+        for value in 1..=100_000u64 {
+            histogram.record(value * 10);
+        }
+        for quantile in [0.1, 0.5, 0.9, 0.99, 0.999] {
+            let exact = 100_000.0 * quantile * 10.0;
+            let estimate = histogram.percentile(quantile) as f64;
+            assert!(
+                estimate >= exact && estimate <= exact * 1.125,
+                "p{} is {} instead of {}",
+                quantile * 100.0,
+                estimate,
+                exact
+            );
+        }
+        assert_eq!(histogram.percentile(1.0), 1_000_000);
+    }
+
+    #[test]
+    fn counts_are_exact_at_bucket_edges() {
+        let mut histogram = Histogram::new();
+        let values: Vec<u64> = (0..2000).map(|value| value * 37).collect();
+        values.iter().for_each(|value| histogram.record(*value));
+        for value in [0, 15, 16, 100, 1000, 10_000, 50_000, 100_000] {
+            let edge = Histogram::bucket_edge(value);
+            assert!(edge > value && edge as f64 <= (value + 1) as f64 * 1.125 + 1.0);
+            let below = values.iter().filter(|recorded| **recorded < edge).count();
+            assert_eq!(
+                histogram.count_at_most(edge - 1),
+                below as u64,
+                "edge {}",
+                edge
+            );
+            // Within a bucket the whole bucket is left out
+            assert!(histogram.count_at_most(value) <= histogram.count_at_most(edge - 1));
+        }
+    }
+
+    #[test]
+    fn merged_histograms_add_up() {
+        let mut a = Histogram::new();
+        let mut b = Histogram::new();
+        (0..100).for_each(|value| a.record(value));
+        (100..300).for_each(|value| b.record(value));
+        a.merge(&b);
+        assert_eq!(a.count(), 300);
+        assert_eq!(a.max(), 299);
+        assert_eq!(a.sum(), (0..300).sum::<u64>());
+        assert_eq!(a.count_at_most(15), 16);
+    }
+
+    #[test]
+    fn reasons_are_capped() {
+        let mut metrics = Metrics::default();
+        for player in 0..MAX_REASONS + 50 {
+            metrics.record_kick(&format!("Player{} was banned", player));
+        }
+        metrics.record_kick("Player0 was banned");
+        metrics.record_kick(&"x".repeat(1000));
+        assert_eq!(metrics.kick_reasons.len(), MAX_REASONS + 1);
+        assert_eq!(metrics.kick_reasons["Player0 was banned"], 2);
+        assert_eq!(metrics.kick_reasons[OTHER_REASONS], 51);
+
+        // Merging the threads keeps the cap as well
+        let mut merged = Metrics::default();
+        merged.merge(&metrics);
+        merged.merge(&metrics);
+        assert_eq!(merged.kick_reasons.len(), MAX_REASONS + 1);
+        assert_eq!(merged.kick_reasons[OTHER_REASONS], 102);
+
+        let mut long = Metrics::default();
+        long.record_kick(&"é".repeat(1000));
+        let reason = long.kick_reasons.keys().next().unwrap();
+        assert_eq!(reason.chars().count(), MAX_REASON_CHARS + 3);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::packet_utils::Buf;
+use crate::Error;
+
+/// A decoded NBT tag
+#[derive(Debug, Clone, PartialEq)]
+pub enum Nbt {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Nbt>),
+    Compound(Vec<(String, Nbt)>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Nbt {
+    pub fn get(&self, key: &str) -> Option<&Nbt> {
+        match self {
+            Nbt::Compound(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, tag)| tag),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Nbt::String(string) => Some(string),
+            _ => None,
+        }
+    }
+}
+
+/// Compounds and lists nested deeper than this are rejected, like vanilla does
+const MAX_DEPTH: u32 = 512;
+
+/// Reads a nameless root tag as sent over the network since 1.20.2, `None` for TAG_End.
+///
+/// Malformed NBT is an error rather than a panic, the server may send anything.
+pub fn read_network_nbt(buf: &mut Buf) -> Result<Option<Nbt>, Error> {
+    let tag = read_byte(buf)?;
+    if tag == 0 {
+        return Ok(None);
+    }
+    read_payload(buf, tag, 0).map(Some)
+}
+
+/// Makes sure `length` more bytes can be read
+fn ensure(buf: &Buf, length: u64) -> Result<(), Error> {
+    let remaining = buf.remaining();
+    if (remaining as u64) < length {
+        return Err(format!("nbt ends early, {} bytes left of {}", remaining, length).into());
+    }
+    Ok(())
+}
+
+fn read_byte(buf: &mut Buf) -> Result<u8, Error> {
+    ensure(buf, 1)?;
+    Ok(buf.read_byte())
+}
+
+/// Reads the length of an array or list, negative lengths count as empty
+fn read_length(buf: &mut Buf, element_size: u64) -> Result<u32, Error> {
+    ensure(buf, 4)?;
+    let length = (buf.read_u32() as i32).max(0) as u32;
+    ensure(buf, length as u64 * element_size)?;
+    Ok(length)
+}
+
+fn read_string(buf: &mut Buf) -> Result<String, Error> {
+    ensure(buf, 2)?;
+    let length = buf.read_u16();
+    ensure(buf, length as u64)?;
+    // Java's modified UTF-8 only differs for nul and supplementary characters
+    Ok(String::from_utf8_lossy(buf.read_bytes(length as u32)).into_owned())
+}
+
+fn read_payload(buf: &mut Buf, tag: u8, depth: u32) -> Result<Nbt, Error> {
+    let nbt = match tag {
+        1 => Nbt::Byte(read_byte(buf)? as i8),
+        2 => {
+            ensure(buf, 2)?;
+            Nbt::Short(buf.read_u16() as i16)
+        }
+        3 => {
+            ensure(buf, 4)?;
+            Nbt::Int(buf.read_u32() as i32)
+        }
+        4 => {
+            ensure(buf, 8)?;
+            Nbt::Long(buf.read_u64() as i64)
+        }
+        5 => {
+            ensure(buf, 4)?;
+            Nbt::Float(buf.read_f32())
+        }
+        6 => {
+            ensure(buf, 8)?;
+            Nbt::Double(buf.read_f64())
+        }
+        7 => {
+            let length = read_length(buf, 1)?;
+            Nbt::ByteArray(buf.read_bytes(length).to_vec())
+        }
+        8 => Nbt::String(read_string(buf)?),
+        9 | 10 if depth >= MAX_DEPTH => {
+            return Err(format!("nbt nested deeper than {}", MAX_DEPTH).into())
+        }
+        9 => {
+            let element = read_byte(buf)?;
+            // Every element takes at least a byte, except in lists of TAG_End
+            let length = read_length(buf, if element == 0 { 0 } else { 1 })?;
+            Nbt::List(
+                (0..length)
+                    .map(|_| read_payload(buf, element, depth + 1))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        10 => {
+            let mut entries = Vec::new();
+            loop {
+                let tag = read_byte(buf)?;
+                if tag == 0 {
+                    break;
+                }
+                let name = read_string(buf)?;
+                entries.push((name, read_payload(buf, tag, depth + 1)?));
+            }
+            Nbt::Compound(entries)
+        }
+        11 => {
+            let length = read_length(buf, 4)?;
+            Nbt::IntArray((0..length).map(|_| buf.read_u32() as i32).collect())
+        }
+        12 => {
+            let length = read_length(buf, 8)?;
+            Nbt::LongArray((0..length).map(|_| buf.read_u64() as i64).collect())
+        }
+        _ => return Err(format!("invalid nbt tag {}", tag).into()),
+    };
+    Ok(nbt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(bytes: &[u8]) -> Result<Option<Nbt>, Error> {
+        let mut buf = Buf::new();
+        buf.write_bytes(bytes);
+        read_network_nbt(&mut buf)
+    }
+
+    fn name(buf: &mut Buf, name: &str) {
+        buf.write_u16(name.len() as u16);
+        buf.write_bytes(name.as_bytes());
+    }
+
+    #[test]
+    fn reads_nested_compounds() {
+        let mut buf = Buf::new();
+        buf.write_u8(10);
+        buf.write_u8(8);
+        name(&mut buf, "text");
+        name(&mut buf, "hi");
+        buf.write_u8(9);
+        name(&mut buf, "extra");
+        buf.write_u8(3);
+        buf.write_u32(2);
+        buf.write_u32(-1i32 as u32);
+        buf.write_u32(7);
+        buf.write_u8(12);
+        name(&mut buf, "longs");
+        buf.write_u32(1);
+        buf.write_u64(u64::MAX);
+        buf.write_u8(1);
+        name(&mut buf, "bold");
+        buf.write_u8(1);
+        buf.write_u8(0);
+        let nbt = read_network_nbt(&mut buf).unwrap().unwrap();
+        assert_eq!(buf.remaining(), 0);
+        assert_eq!(nbt.get("text").and_then(Nbt::as_str), Some("hi"));
+        assert_eq!(
+            nbt.get("extra"),
+            Some(&Nbt::List(vec![Nbt::Int(-1), Nbt::Int(7)]))
+        );
+        assert_eq!(nbt.get("longs"), Some(&Nbt::LongArray(vec![-1])));
+        assert_eq!(nbt.get("bold"), Some(&Nbt::Byte(1)));
+        assert_eq!(nbt.get("missing"), None);
+    }
+
+    #[test]
+    fn reads_end_and_root_strings() {
+        assert_eq!(read(&[0]).unwrap(), None);
+        assert_eq!(
+            read(&[8, 0, 2, b'o', b'k']).unwrap(),
+            Some(Nbt::String("ok".to_owned()))
+        );
+        // Negative lengths count as empty
+        assert_eq!(
+            read(&[7, 0xff, 0xff, 0xff, 0xff]).unwrap(),
+            Some(Nbt::ByteArray(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn malformed_nbt_is_an_error() {
+        // Nothing at all
+        assert!(read(&[]).is_err());
+        // Unknown tag
+        assert!(read(&[13]).is_err());
+        // Truncated int and string
+        assert!(read(&[3, 0, 0]).is_err());
+        assert!(read(&[8, 0, 5, b'a']).is_err());
+        // Arrays and lists longer than the packet, without allocating for them
+        assert!(read(&[11, 0x7f, 0xff, 0xff, 0xff]).is_err());
+        assert!(read(&[9, 1, 0x7f, 0xff, 0xff, 0xff]).is_err());
+        // Compound that never ends
+        assert!(read(&[10, 1, 0, 1, b'a', 1]).is_err());
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        // A list holding a list and so on, `lists` deep
+        let nested = |lists: u32| {
+            let mut bytes = vec![9];
+            for _ in 1..lists {
+                bytes.extend_from_slice(&[9, 0, 0, 0, 1]);
+            }
+            bytes.extend_from_slice(&[0, 0, 0, 0, 0]);
+            read(&bytes)
+        };
+        assert!(nested(MAX_DEPTH).is_ok());
+        assert!(nested(MAX_DEPTH + 1).is_err());
+    }
+}
@@ -1,10 +1,11 @@
 use crate::packet_utils::Buf;
-use crate::{packet_processors, Bot, Compression, Error};
+use crate::{packet_processors, Bot, Compression, Context, Error};
 use std::io::{ErrorKind, Read, Write};
 
-pub fn read_socket(bot: &mut Bot, packet: &mut Buf) -> bool {
+/// Returns whether more data may be available, or why the socket can no longer be read
+pub fn read_socket(bot: &mut Bot, packet: &mut Buf) -> Result<bool, String> {
     if bot.kicked {
-        return false;
+        return Ok(false);
     }
 
     let w_i = packet.get_writer_index();
@@ -12,21 +13,22 @@ pub fn read_socket(bot: &mut Bot, packet: &mut Buf) -> bool {
     match result {
         Ok(0) => {
             println!("Peer closed socket");
-            bot.kicked = true;
-            false
+            Err("Peer closed socket".to_owned())
         }
         Ok(written) => {
+            if let Some(encryption) = &mut bot.encryption {
+                encryption.decrypt(&mut packet.buffer[w_i as usize..w_i as usize + written]);
+            }
             packet.set_writer_index(packet.get_writer_index() + written as u32);
-            true
+            Ok(true)
         }
         Err(e) if e.kind() == ErrorKind::WouldBlock => {
             // Break out of loop
-            false
+            Ok(false)
         }
         Err(e) => {
             println!("unable to read socket: {:?}", e);
-            bot.kicked = true;
-            false
+            Err(format!("unable to read socket: {}", e.kind()))
         }
     }
 }
@@ -49,24 +51,29 @@ pub fn process_packet(
     bot: &mut Bot,
     packet_buf: &mut Buf,
     decompression_buf: &mut Buf,
-    compression: &mut Compression,
+    ctx: &mut Context,
 ) {
     packet_buf.set_reader_index(0);
     packet_buf.set_writer_index(0);
 
     // Read new packets
     unbuffer(packet_buf, &mut bot.buffering_buf);
-    while read_socket(bot, packet_buf) {
-        let len = packet_buf.buffer.len();
-
-        // Reallocate if full
-        if packet_buf.get_writer_index() == len as u32 {
-            packet_buf.buffer.reserve(len);
-            unsafe {
-                packet_buf.buffer.set_len(len * 2);
+    // Packets that arrived before the socket was closed are still processed, they usually
+    // contain the reason the server disconnected the bot
+    let closed = loop {
+        match read_socket(bot, packet_buf) {
+            Ok(true) => {
+                let len = packet_buf.buffer.len();
+
+                // Reallocate if full
+                if packet_buf.get_writer_index() == len as u32 {
+                    packet_buf.buffer.resize(len * 2, 0);
+                }
             }
+            Ok(false) => break None,
+            Err(reason) => break Some(reason),
         }
-    }
+    };
     if bot.kicked {
         return;
     }
@@ -76,7 +83,8 @@ pub fn process_packet(
     // Process all of the Minecraft packets received
     loop {
         // Handle packet that have an incomplete size field
-        if packet_buf.get_writer_index() as u32 - next < 3 {
+        let remaining = &packet_buf.buffer[next as usize..packet_buf.get_writer_index() as usize];
+        if !remaining.iter().take(3).any(|byte| byte & 0b10000000 == 0) {
             buffer(packet_buf, &mut bot.buffering_buf);
             break;
         }
@@ -84,7 +92,8 @@ pub fn process_packet(
         // Read packet size
         let tuple = packet_buf.read_var_u32();
         let size = tuple.0 as usize;
-        next += tuple.0 + tuple.1;
+        let wire_size = tuple.0 + tuple.1;
+        next += wire_size;
 
         // Skip packets of 0 length
         if size == 0 {
@@ -124,7 +133,7 @@ pub fn process_packet(
                             packet_buf.get_reader_index(),
                             packet_buf.get_writer_index()
                         );
-                        bot.kicked = true;
+                        bot.kick("invalid compressed packet");
                         break;
                     }
 
@@ -132,26 +141,30 @@ pub fn process_packet(
                     match decompress_packet(
                         real_length,
                         &packet_buf.buffer[start..end],
-                        compression,
+                        &mut ctx.compression,
                         decompression_buf,
                     ) {
-                        Ok(x) => x,
+                        Ok(_) => ctx
+                            .metrics
+                            .compression_in
+                            .add(real_length as u64, (end - start) as u64),
                         Err(err) => {
                             println!("decompression error: {}", err);
-                            bot.kicked = true;
+                            bot.kick(format!("decompression error: {}", err));
                             break;
                         }
                     };
                 }
 
-                packet_processors::process_decode(decompression_buf, bot, compression);
+                packet_processors::process_decode(decompression_buf, bot, ctx, wire_size);
             } else {
-                packet_processors::process_decode(packet_buf, bot, compression);
+                packet_processors::process_decode(packet_buf, bot, ctx, wire_size);
             }
         } else {
-            packet_processors::process_decode(packet_buf, bot, compression);
+            packet_processors::process_decode(packet_buf, bot, ctx, wire_size);
         }
-        if bot.kicked {
+        // Anything after a Transfer was meant for the old connection
+        if bot.kicked || bot.transfer.is_some() {
             break;
         }
 
@@ -161,25 +174,54 @@ pub fn process_packet(
             break;
         }
     }
+
+    if let Some(reason) = closed {
+        if bot.transfer.is_none() {
+            bot.kick(reason);
+        }
+    }
 }
 
 impl Bot {
-    pub fn send_packet(&mut self, buf: Buf, compression: &mut Compression) {
+    pub fn send_packet(&mut self, buf: Buf, ctx: &mut Context) {
         if self.kicked {
             return;
         }
         let mut packet = buf;
+
+        packet.mark_reader();
+        let packet_id = packet.read_var_u32().0;
+        packet.reset_reader();
+
         if self.compression_threshold > 0 {
-            packet = packet_processors::PacketCompressor::process_write(packet, self, compression)
-                .unwrap();
+            let uncompressed = packet.get_writer_index();
+            packet = packet_processors::PacketCompressor::process_write(
+                packet,
+                self,
+                &mut ctx.compression,
+            )
+            .unwrap();
+            if uncompressed as i32 > self.compression_threshold {
+                ctx.metrics
+                    .compression_out
+                    .add(uncompressed as u64, packet.get_writer_index() as u64);
+            }
         }
         packet = packet_processors::PacketFramer::process_write(packet);
+        if let Some(encryption) = &mut self.encryption {
+            let range = packet.get_reader_index() as usize..packet.get_writer_index() as usize;
+            encryption.encrypt(&mut packet.buffer[range]);
+        }
+        let bytes = packet.get_writer_index() - packet.get_reader_index();
         match self.stream.write_all(
             &packet.buffer[packet.get_reader_index() as usize..packet.get_writer_index() as usize],
         ) {
-            Ok(_) => {}
+            Ok(_) => {
+                self.packets_out.add(bytes as u64);
+                ctx.metrics.record_out(self.state, packet_id, bytes as u64);
+            }
             Err(e) => {
-                self.kicked = true;
+                self.kick(format!("could not write to socket: {}", e.kind()));
                 println!("could not write to buf: {}", e);
             }
         }
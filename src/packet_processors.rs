@@ -1,67 +1,102 @@
 use libdeflater::Compressor;
 
 use crate::packet_utils::Buf;
+use crate::protocol::Clientbound;
 use crate::states::{config, login, play, status};
-use crate::{Bot, Compression, Error, ProtocolState};
+use crate::{behaviour, Bot, Compression, Context, Error, ProtocolState};
 
-pub type PacketHandler = fn(buffer: &mut Buf, bot: &mut Bot, compression: &mut Compression);
+pub type PacketHandler = fn(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context);
 
 pub struct PacketFramer {}
 
 pub struct PacketCompressor {}
 
-pub fn lookup_packet(state: ProtocolState, packet: u8) -> Option<PacketHandler> {
-    match state {
-        ProtocolState::Login => match packet {
-            0x00 => return Some(play::process_kick),
-            0x01 => return Some(login::process_encryption_request_packet),
-            0x02 => return Some(login::process_login_success_packet),
-            0x03 => return Some(login::process_set_compression_packet),
-            0x04 => return Some(login::process_plugin_message_request),
-            0x05 => return Some(login::process_cookie_request_packet),
-            _ => {}
-        },
+pub fn lookup_packet(state: ProtocolState, packet: Clientbound) -> Option<PacketHandler> {
+    let handler: PacketHandler = match (state, packet) {
+        (ProtocolState::Status, Clientbound::StatusResponse) => status::process_status_response,
+        (ProtocolState::Status, Clientbound::PongResponse) => status::process_pong,
 
-        ProtocolState::Status => match packet {
-            0x00 => return Some(status::process_status_response),
-            0x01 => return Some(status::process_pong),
-            _ => {}
-        },
+        (ProtocolState::Login, Clientbound::Disconnect) => play::process_kick,
+        (ProtocolState::Login, Clientbound::EncryptionRequest) => {
+            login::process_encryption_request_packet
+        }
+        (ProtocolState::Login, Clientbound::LoginSuccess) => login::process_login_success_packet,
+        (ProtocolState::Login, Clientbound::SetCompression) => {
+            login::process_set_compression_packet
+        }
+        (ProtocolState::Login, Clientbound::LoginPluginRequest) => {
+            login::process_plugin_message_request
+        }
+        (ProtocolState::Login, Clientbound::CookieRequest) => login::process_cookie_request_packet,
 
-        ProtocolState::Config => match packet {
-            0x00 => return Some(config::process_cookie_request_packet),
-            0x02 => return Some(play::process_kick),
-            0x03 => return Some(config::process_finish_configuration),
-            0x04 => return Some(config::process_keep_alive_packet),
-            0x05 => return Some(config::process_ping),
-            0x09 => return Some(config::process_resource_pack),
-            0x0B => return Some(config::process_transfer),
-            0x0E => return Some(config::process_known_packs),
-            _ => {}
-        },
+        (ProtocolState::Config, Clientbound::CookieRequest) => {
+            config::process_cookie_request_packet
+        }
+        (ProtocolState::Config, Clientbound::Disconnect) => play::process_kick,
+        (ProtocolState::Config, Clientbound::FinishConfiguration) => {
+            config::process_finish_configuration
+        }
+        (ProtocolState::Config, Clientbound::KeepAlive) => config::process_keep_alive_packet,
+        (ProtocolState::Config, Clientbound::Ping) => config::process_ping,
+        (ProtocolState::Config, Clientbound::AddResourcePack) => config::process_resource_pack,
+        (ProtocolState::Config, Clientbound::StoreCookie) => config::process_store_cookie,
+        (ProtocolState::Config, Clientbound::Transfer) => config::process_transfer,
+        (ProtocolState::Config, Clientbound::KnownPacks) => config::process_known_packs,
 
-        ProtocolState::Play => {
-            match packet {
-                0x16 => return Some(play::process_cookie_request_packet), // KEEP_ALIVE
-                0x26 => return Some(play::process_keep_alive_packet),     // KEEP_ALIVE
-                0x2B => return Some(play::process_join_game),             // JOIN_GAME
-                0x1D => return Some(play::process_kick),                  // DISCONNECT
-                0x40 => return Some(play::process_teleport), // PLAYER_POSITION_AND_LOOK
-                0x73 => return Some(config::process_transfer),
-                _ => {}
-            }
+        (ProtocolState::Play, Clientbound::CookieRequest) => play::process_cookie_request_packet,
+        (ProtocolState::Play, Clientbound::KeepAlive) => play::process_keep_alive_packet,
+        (ProtocolState::Play, Clientbound::Login) => play::process_join_game,
+        (ProtocolState::Play, Clientbound::Ping) => play::process_ping,
+        (ProtocolState::Play, Clientbound::PingResponse) => play::process_ping_response,
+        (ProtocolState::Play, Clientbound::Disconnect) => play::process_kick,
+        (ProtocolState::Play, Clientbound::SynchronizePlayerPosition) => play::process_teleport,
+        (ProtocolState::Play, Clientbound::Respawn) => play::process_respawn,
+        (ProtocolState::Play, Clientbound::ChunkData) => play::process_chunk_data,
+        (ProtocolState::Play, Clientbound::ChunkBatchFinished) => play::process_chunk_batch,
+        (ProtocolState::Play, Clientbound::UnloadChunk) => play::process_unload_chunk,
+        (ProtocolState::Play, Clientbound::BlockUpdate) => play::process_block_update,
+        (ProtocolState::Play, Clientbound::SectionBlocksUpdate) => {
+            play::process_section_blocks_update
         }
-    }
-    None
+        (ProtocolState::Play, Clientbound::SpawnEntity) => play::process_spawn_entity,
+        (ProtocolState::Play, Clientbound::RemoveEntities) => play::process_remove_entities,
+        (ProtocolState::Play, Clientbound::UpdateEntityPosition) => play::process_entity_position,
+        (ProtocolState::Play, Clientbound::UpdateEntityPositionAndRotation) => {
+            play::process_entity_position_rotation
+        }
+        (ProtocolState::Play, Clientbound::UpdateEntityRotation) => play::process_entity_rotation,
+        (ProtocolState::Play, Clientbound::TeleportEntity) => play::process_teleport_entity,
+        (ProtocolState::Play, Clientbound::SetEntityMetadata) => play::process_entity_metadata,
+        (ProtocolState::Play, Clientbound::SystemChat) => play::process_system_chat,
+        (ProtocolState::Play, Clientbound::PlayerChat) => play::process_player_chat,
+        (ProtocolState::Play, Clientbound::DisguisedChat) => play::process_disguised_chat,
+        (ProtocolState::Play, Clientbound::StoreCookie) => config::process_store_cookie,
+        (ProtocolState::Play, Clientbound::Transfer) => config::process_transfer,
+
+        _ => return None,
+    };
+    Some(handler)
 }
 
 pub fn process_decode(
     buffer: &mut Buf,
     bot: &mut Bot,
-    compression: &mut Compression,
+    ctx: &mut Context,
+    wire_size: u32,
 ) -> Option<()> {
-    let packet_id = buffer.read_var_u32().0 as u8;
-    (lookup_packet(bot.state, packet_id)?)(buffer, bot, compression);
+    let packet_id = buffer.read_var_u32().0;
+    bot.packets_in.add(wire_size as u64);
+    ctx.metrics
+        .record_in(bot.state, packet_id, wire_size as u64);
+    let packet = ctx.protocol.clientbound(bot.state, packet_id)?;
+    if bot.state == ProtocolState::Play {
+        let start = buffer.get_reader_index();
+        behaviour::run(bot, |behaviour, bot| {
+            behaviour.on_packet(bot, ctx, packet, buffer)
+        });
+        buffer.set_reader_index(start);
+    }
+    (lookup_packet(bot.state, packet)?)(buffer, bot, ctx);
     Some(())
 }
 
@@ -103,13 +138,17 @@ pub fn compress_packet(
     compressor: &mut Compressor,
     compression_buffer: &mut Buf,
 ) -> Result<(), Error> {
-    compression_buffer.write_var_u32(packet.get_writer_index());
-    compression_buffer
-        .ensure_writable(compressor.zlib_compress_bound(packet.get_writer_index() as usize) as u32);
+    let length = packet.get_writer_index() as usize;
+    compression_buffer.write_var_u32(length as u32);
+    compression_buffer.ensure_writable(compressor.zlib_compress_bound(length) as u32);
 
-    //compress
-    let written = compressor.zlib_compress(&packet.buffer, &mut compression_buffer.buffer)?;
-    compression_buffer.set_writer_index(written as u32);
+    //compress after the length header
+    let start = compression_buffer.get_writer_index();
+    let written = compressor.zlib_compress(
+        &packet.buffer[..length],
+        &mut compression_buffer.buffer[start as usize..],
+    )?;
+    compression_buffer.set_writer_index(start + written as u32);
 
     Ok(())
 }
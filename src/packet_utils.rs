@@ -1,8 +1,10 @@
 use std::convert::TryInto;
-use std::intrinsics::copy_nonoverlapping;
 use std::io::Write;
+use std::ptr::copy_nonoverlapping;
 use std::{io, mem};
 
+use crate::Error;
+
 pub struct Buf {
     pub buffer: Vec<u8>,
     write_index: u32,
@@ -53,11 +55,7 @@ impl Buf {
         let src_usize = src as usize;
         let dst_usize = dst as usize;
         let size = mem::size_of::<T>().checked_mul(count).unwrap();
-        let diff = if src_usize > dst_usize {
-            src_usize - dst_usize
-        } else {
-            dst_usize - src_usize
-        };
+        let diff = src_usize.abs_diff(dst_usize);
         // If the absolute distance between the ptrs is at least as big as the size of the buffer,
         // they do not overlap.
         diff >= size
@@ -100,12 +98,8 @@ impl Buf {
         if self.buffer.len() < (self.write_index + num) as usize {
             let new_bytes = self.write_index + num - self.buffer.len() as u32;
 
-            self.buffer.reserve(new_bytes as usize);
-            unsafe {
-                self.buffer.set_len((self.write_index + num) as usize);
-            }
-
-            //self.buffer.extend(vec![0; new_bytes as usize]); // safe alt for debugging
+            self.buffer
+                .resize(self.buffer.len() + new_bytes as usize, 0);
         }
     }
 
@@ -262,25 +256,62 @@ impl Buf {
         &self.buffer[range]
     }
 
-    pub fn read_sized_string(&mut self) -> &str {
+    /// Strings longer than the rest of the packet or not valid UTF-8 are an error
+    pub fn read_sized_string(&mut self) -> Result<&str, Error> {
         let length = self.read_var_u32().0;
-        let bytes = self.read_bytes(length);
-        std::str::from_utf8(bytes).expect("Error occurred while parsing string")
+        self.read_string_bytes(length)
+    }
+
+    pub fn read_short_sized_string(&mut self) -> Result<&str, Error> {
+        let length = self.read_u16() as u32;
+        self.read_string_bytes(length)
     }
 
-    pub fn read_short_sized_string(&mut self) -> &str {
-        let length = self.read_u16();
-        let bytes = self.read_bytes(length as u32);
-        std::str::from_utf8(bytes).expect("Error occurred while parsing string")
+    fn read_string_bytes(&mut self, length: u32) -> Result<&str, Error> {
+        if length > self.remaining() {
+            return Err(format!(
+                "string of {} bytes but only {} left in the packet",
+                length,
+                self.remaining()
+            )
+            .into());
+        }
+        std::str::from_utf8(self.read_bytes(length))
+            .map_err(|err| format!("invalid string: {}", err).into())
+    }
+
+    /// Byte arrays prefixed with their length, a length longer than the rest of the packet is
+    /// an error
+    pub fn read_sized_bytes(&mut self) -> Result<&[u8], Error> {
+        let length = self.read_var_u32().0;
+        if length > self.remaining() {
+            return Err(format!(
+                "{} bytes but only {} left in the packet",
+                length,
+                self.remaining()
+            )
+            .into());
+        }
+        Ok(self.read_bytes(length))
     }
 
-    pub fn read_var_u32_slice(&mut self) -> Vec<u32> {
+    /// Every VarInt takes at least a byte, a length longer than the rest of the packet is an
+    /// error instead of a huge allocation
+    pub fn read_var_u32_slice(&mut self) -> Result<Vec<u32>, Error> {
         let length = self.read_var_u32().0;
+        if length > self.remaining() {
+            return Err(format!(
+                "{} VarInts but only {} bytes left in the packet",
+                length,
+                self.remaining()
+            )
+            .into());
+        }
         let mut nums: Vec<u32> = Vec::with_capacity(length as usize);
         for _ in 0..length {
             nums.push(self.read_var_u32().0);
         }
-        nums
+        Ok(nums)
     }
 
     pub fn read_var_u32(&mut self) -> (u32, u32) {
@@ -319,11 +350,21 @@ impl Buf {
         (result, num_read)
     }
 
-    pub fn read_block_position(&mut self) -> (i32, u8, i32) {
-        let value = self.read_u64();
+    /// Reads a block position as x, y and z
+    pub fn read_block_position(&mut self) -> (i32, i32, i32) {
+        let value = self.read_u64() as i64;
         let x = (value >> 38) as i32;
-        let z = (value >> 12) as i32;
-        let y = (value & 0xFFF) as u8;
+        let y = (value << 52 >> 52) as i32;
+        let z = (value << 26 >> 38) as i32;
+        (x, y, z)
+    }
+
+    /// Reads a chunk section position as x, y and z
+    pub fn read_section_position(&mut self) -> (i32, i32, i32) {
+        let value = self.read_u64() as i64;
+        let x = (value >> 42) as i32;
+        let y = (value << 44 >> 44) as i32;
+        let z = (value << 22 >> 42) as i32;
         (x, y, z)
     }
 
@@ -363,6 +404,11 @@ impl Buf {
         self.write_index
     }
 
+    /// Bytes left to read
+    pub fn remaining(&self) -> u32 {
+        self.write_index - self.read_index
+    }
+
     pub fn get_var_u32_size(num: u32) -> u32 {
         if num & 0xFFFFFF80 == 0 {
             1
@@ -408,3 +454,49 @@ impl Write for Buf {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buf(bytes: &[u8]) -> Buf {
+        let mut buf = Buf::new();
+        buf.write_bytes(bytes);
+        buf
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        let mut buf = Buf::new();
+        buf.write_sized_str("héllo");
+        buf.write_short_sized_str("");
+        assert_eq!(buf.read_sized_string().unwrap(), "héllo");
+        assert_eq!(buf.read_short_sized_string().unwrap(), "");
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn malformed_strings_are_an_error() {
+        // Longer than the packet
+        assert!(buf(&[5, b'a', b'b']).read_sized_string().is_err());
+        assert!(buf(&[0xff, 0xff, b'a']).read_short_sized_string().is_err());
+        // Not UTF-8
+        assert!(buf(&[2, 0xc3, 0x28]).read_sized_string().is_err());
+    }
+
+    #[test]
+    fn byte_arrays_are_capped_by_the_packet() {
+        assert_eq!(buf(&[2, 7, 8, 9]).read_sized_bytes().unwrap(), [7, 8]);
+        assert!(buf(&[4, 7, 8, 9]).read_sized_bytes().is_err());
+    }
+
+    #[test]
+    fn var_int_arrays_are_capped_by_the_packet() {
+        let mut ok = buf(&[3, 1, 0xac, 0x02, 0]);
+        assert_eq!(ok.read_var_u32_slice().unwrap(), vec![1, 300, 0]);
+        // A length of about two billion in a few bytes
+        assert!(buf(&[0xff, 0xff, 0xff, 0xff, 0x07, 1])
+            .read_var_u32_slice()
+            .is_err());
+    }
+}
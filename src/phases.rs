@@ -0,0 +1,266 @@
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// One step of a load profile, the phases of a run are played one after another
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Phase {
+    /// Move the number of bots to `to` linearly over `secs` seconds
+    Ramp { to: u32, secs: f64 },
+    /// Keep the number of bots for `secs` seconds, kicked bots are replaced
+    Hold { secs: f64 },
+    /// Add `by` bots, or remove them when negative, at the join and leave rates
+    Step { by: i64 },
+}
+
+impl Phase {
+    fn secs(&self) -> f64 {
+        match *self {
+            Phase::Ramp { secs, .. } | Phase::Hold { secs } => secs,
+            Phase::Step { .. } => 0.0,
+        }
+    }
+
+    /// Number of bots once the phase has started from `from` bots
+    fn target(&self, from: i64) -> i64 {
+        match *self {
+            Phase::Ramp { to, .. } => to as i64,
+            Phase::Hold { .. } => from,
+            Phase::Step { by } => from + by,
+        }
+    }
+}
+
+impl FromStr for Phase {
+    type Err = Error;
+
+    /// Parses `ramp:<bots>:<secs>`, `hold:<secs>` and `step:<bots>`
+    fn from_str(phase: &str) -> Result<Phase, Error> {
+        let invalid = || {
+            format!(
+                "invalid phase {}, expected ramp:<bots>:<secs>, hold:<secs> or step:<bots>",
+                phase
+            )
+        };
+        let parts: Vec<&str> = phase.split(':').collect();
+        let parsed = match parts.as_slice() {
+            ["ramp", to, secs] => to
+                .parse()
+                .ok()
+                .zip(secs.parse().ok())
+                .map(|(to, secs)| Phase::Ramp { to, secs }),
+            ["hold", secs] => secs.parse().ok().map(|secs| Phase::Hold { secs }),
+            ["step", by] => by
+                .trim_start_matches('+')
+                .parse()
+                .ok()
+                .map(|by| Phase::Step { by }),
+            _ => None,
+        };
+        parsed.ok_or_else(|| invalid().into())
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Ramp { to, secs } => write!(f, "ramp to {} bots over {}s", to, secs),
+            Phase::Hold { secs } => write!(f, "hold for {}s", secs),
+            Phase::Step { by } => write!(f, "step by {:+} bots", by),
+        }
+    }
+}
+
+/// Checks that every phase can be played, returns the highest number of bots asked for
+pub fn validate(phases: &[Phase]) -> Result<u32, Error> {
+    let mut bots = 0i64;
+    let mut peak = 0i64;
+    for (index, phase) in phases.iter().enumerate() {
+        let secs = phase.secs();
+        if !secs.is_finite() || secs < 0.0 || (secs == 0.0 && !matches!(phase, Phase::Step { .. }))
+        {
+            return Err(format!(
+                "phase {} ({}) must last a positive number of seconds",
+                index + 1,
+                phase
+            )
+            .into());
+        }
+        bots = phase.target(bots);
+        if !(0..=u32::MAX as i64).contains(&bots) {
+            return Err(format!(
+                "phase {} ({}) leaves {} bots online",
+                index + 1,
+                phase,
+                bots
+            )
+            .into());
+        }
+        peak = peak.max(bots);
+    }
+    if let Some(Phase::Step { .. }) = phases.last() {
+        return Err("a step takes no time, follow the last one with a hold".into());
+    }
+    Ok(peak as u32)
+}
+
+/// Where a run is in its phases
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    /// Index of the current phase
+    pub phase: usize,
+    /// Bots that should be online across all threads
+    pub target: f64,
+    /// Bots joining per second across all threads while under the target
+    pub join_rate: f64,
+    /// Bots leaving per second across all threads while over the target
+    pub leave_rate: f64,
+}
+
+/// Looks up the phase playing `elapsed` seconds into the run, `None` once the last one is over.
+///
+/// Ramps join and leave at their own pace, holds, steps and flat ramps at `join_rate` and
+/// `leave_rate`.
+pub fn position(
+    phases: &[Phase],
+    elapsed: f64,
+    join_rate: f64,
+    leave_rate: f64,
+) -> Option<Position> {
+    let mut start = 0.0;
+    let mut bots = 0i64;
+    for (index, phase) in phases.iter().enumerate() {
+        let from = bots;
+        bots = phase.target(from);
+        let secs = phase.secs();
+        if elapsed < start + secs {
+            let position = match *phase {
+                Phase::Ramp { to, secs } if to as i64 != from => {
+                    let progress = (elapsed - start) / secs;
+                    let rate = (to as f64 - from as f64).abs() / secs;
+                    Position {
+                        phase: index,
+                        target: from as f64 + (to as f64 - from as f64) * progress,
+                        join_rate: rate,
+                        leave_rate: rate,
+                    }
+                }
+                _ => Position {
+                    phase: index,
+                    target: bots as f64,
+                    join_rate,
+                    leave_rate,
+                },
+            };
+            return Some(position);
+        }
+        start += secs;
+    }
+    None
+}
+
+/// This thread's share of `total` bots, spread like the bot count is spread across threads
+pub fn share(total: f64, threads: u32, thread: usize) -> u32 {
+    let total = total.floor().max(0.0) as u32;
+    total / threads + (thread < (total % threads) as usize) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phases(specs: &[&str]) -> Vec<Phase> {
+        specs.iter().map(|spec| spec.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn phases_parse() {
+        assert_eq!(
+            "ramp:500:60".parse::<Phase>().unwrap(),
+            Phase::Ramp {
+                to: 500,
+                secs: 60.0
+            }
+        );
+        assert_eq!(
+            "hold:2.5".parse::<Phase>().unwrap(),
+            Phase::Hold { secs: 2.5 }
+        );
+        assert_eq!("step:+20".parse::<Phase>().unwrap(), Phase::Step { by: 20 });
+        assert_eq!(
+            "step:-20".parse::<Phase>().unwrap(),
+            Phase::Step { by: -20 }
+        );
+        for invalid in [
+            "",
+            "ramp:500",
+            "ramp:-1:60",
+            "hold",
+            "hold:x",
+            "step:1:2",
+            "wait:5",
+        ] {
+            assert!(invalid.parse::<Phase>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn validate_returns_the_peak() {
+        let profile = phases(&["ramp:100:10", "step:50", "hold:5", "ramp:20:10", "hold:1"]);
+        assert_eq!(validate(&profile).unwrap(), 150);
+        assert_eq!(validate(&[]).unwrap(), 0);
+
+        assert!(validate(&phases(&["hold:0"])).is_err());
+        assert!(validate(&[Phase::Hold { secs: f64::NAN }]).is_err());
+        assert!(validate(&phases(&["ramp:10:0"])).is_err());
+        assert!(validate(&phases(&["ramp:10:5", "step:-11", "hold:5"])).is_err());
+        assert!(validate(&phases(&["ramp:10:5", "step:5"])).is_err());
+    }
+
+    #[test]
+    fn position_follows_the_phases() {
+        let profile = phases(&["ramp:100:10", "hold:5", "step:-40", "hold:5", "ramp:60:10"]);
+
+        let at = position(&profile, 2.5, 7.0, 3.0).unwrap();
+        assert_eq!(at.phase, 0);
+        assert_eq!(at.target, 25.0);
+        assert_eq!(at.join_rate, 10.0);
+        assert_eq!(at.leave_rate, 10.0);
+
+        // Holds keep the target and join and leave at the given rates
+        let at = position(&profile, 12.0, 7.0, 3.0).unwrap();
+        assert_eq!(at.phase, 1);
+        assert_eq!(at.target, 100.0);
+        assert_eq!((at.join_rate, at.leave_rate), (7.0, 3.0));
+
+        // Steps take no time, the hold after one plays at its target
+        let at = position(&profile, 15.0, 7.0, 3.0).unwrap();
+        assert_eq!(at.phase, 3);
+        assert_eq!(at.target, 60.0);
+
+        // A ramp to the number of bots already online is a hold
+        let at = position(&profile, 25.0, 7.0, 3.0).unwrap();
+        assert_eq!(at.phase, 4);
+        assert_eq!(at.target, 60.0);
+        assert_eq!((at.join_rate, at.leave_rate), (7.0, 3.0));
+
+        assert_eq!(position(&profile, 30.0, 7.0, 3.0), None);
+        assert_eq!(position(&[], 0.0, 7.0, 3.0), None);
+    }
+
+    #[test]
+    fn share_spreads_bots_like_the_bot_count() {
+        for total in [0, 1, 7, 100, 101] {
+            let shares: Vec<u32> = (0..4)
+                .map(|thread| share(total as f64, 4, thread))
+                .collect();
+            assert_eq!(shares.iter().sum::<u32>(), total);
+            assert!(shares.windows(2).all(|pair| pair[0] >= pair[1]));
+            assert!(shares[0] - shares[3] <= 1);
+        }
+        assert_eq!(share(10.9, 3, 0), 4);
+        assert_eq!(share(-5.0, 3, 0), 0);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::world::World;
+use crate::Bot;
+use rand::Rng;
+
+/// Vanilla player movement, in blocks and blocks per tick
+const GRAVITY: f64 = 0.08;
+const DRAG: f64 = 0.98;
+const JUMP_VELOCITY: f64 = 0.42;
+const WALK_SPEED: f64 = 0.216;
+const SPRINT_SPEED: f64 = 0.281;
+const SNEAK_SPEED: f64 = 0.065;
+const HALF_WIDTH: f64 = 0.3;
+const HEIGHT: f64 = 1.8;
+/// Moves are split into steps of this size so falling fast doesn't skip through blocks
+const MAX_STEP: f64 = 0.5;
+/// Entities closer than this many blocks draw the bot towards them
+const FOLLOW_DISTANCE: f64 = 16.0;
+/// Vanilla clients send their position at least once a second, even when standing still
+const POSITION_INTERVAL: u32 = 20;
+
+/// Where and how a bot in the play state is moving, its position is `bot.x`, `bot.y` and `bot.z`
+#[derive(Debug, Clone, Default)]
+pub struct Body {
+    /// Degrees, 0 faces +z and 90 faces -x
+    pub yaw: f32,
+    pub pitch: f32,
+    pub on_ground: bool,
+    /// Vertical speed, positive is up
+    pub velocity: f64,
+    pub sprinting: bool,
+    pub sneaking: bool,
+    /// The last movement packet, `None` makes the next one carry position and rotation
+    sent: Option<Sent>,
+    ticks_since_position: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sent {
+    x: f64,
+    y: f64,
+    z: f64,
+    yaw: f32,
+    pitch: f32,
+    on_ground: bool,
+}
+
+/// The movement packet a vanilla client would send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Movement {
+    PositionAndRotation,
+    Position,
+    Rotation,
+    OnGround,
+}
+
+impl Body {
+    /// The server moved the bot, the next movement packet carries position and rotation
+    pub fn teleported(&mut self) {
+        self.velocity = 0.0;
+        self.on_ground = false;
+        self.sent = None;
+    }
+
+    /// Picks the packet telling the server about the bot's movement since the last one, `None`
+    /// if nothing changed
+    pub fn movement(&mut self, x: f64, y: f64, z: f64) -> Option<Movement> {
+        let now = Sent {
+            x,
+            y,
+            z,
+            yaw: self.yaw,
+            pitch: self.pitch,
+            on_ground: self.on_ground,
+        };
+        let movement = match self.sent {
+            None => Movement::PositionAndRotation,
+            Some(sent) => {
+                let distance = (x - sent.x).powi(2) + (y - sent.y).powi(2) + (z - sent.z).powi(2);
+                let moved =
+                    distance > 2.0e-4f64.powi(2) || self.ticks_since_position >= POSITION_INTERVAL;
+                let rotated = self.yaw != sent.yaw || self.pitch != sent.pitch;
+                match (moved, rotated) {
+                    (true, true) => Movement::PositionAndRotation,
+                    (true, false) => Movement::Position,
+                    (false, true) => Movement::Rotation,
+                    (false, false) if self.on_ground != sent.on_ground => Movement::OnGround,
+                    (false, false) => return None,
+                }
+            }
+        };
+        if let Movement::PositionAndRotation | Movement::Position = movement {
+            self.ticks_since_position = 0;
+        }
+        self.sent = Some(now);
+        Some(movement)
+    }
+}
+
+/// Simulates one tick of a bot wandering around: it walks straight ahead, turns now and then,
+/// often towards an entity close by, or when it runs into a wall, jumps up single blocks and
+/// falls when there is nothing below.
+///
+/// Blocks in chunks the bot hasn't received stop it, like a vanilla client waiting for them.
+pub fn tick(bot: &mut Bot, world: &World) {
+    let rng = &mut bot.rng;
+    let body = &mut bot.body;
+    body.ticks_since_position += 1;
+
+    if rng.gen_ratio(1, 60) {
+        let (x, y, z) = (bot.x, bot.y, bot.z);
+        let nearby = bot.entities.nearest(x, y, z, |entity| {
+            entity.distance_squared(x, y, z) < FOLLOW_DISTANCE.powi(2)
+        });
+        match nearby {
+            // Head for an entity close by half of the time
+            Some((_, entity)) if rng.gen() => {
+                let (dx, dy, dz) = (entity.x - x, entity.y - y, entity.z - z);
+                body.yaw = (-dx).atan2(dz).to_degrees().rem_euclid(360.0) as f32;
+                body.pitch = -dy.atan2(dx.hypot(dz)).to_degrees() as f32;
+            }
+            _ => {
+                body.yaw = (body.yaw + rng.gen_range(-90.0..90.0)).rem_euclid(360.0);
+                body.pitch = rng.gen_range(-30.0..30.0);
+            }
+        }
+    }
+    if body.on_ground && rng.gen_ratio(1, 200) {
+        body.velocity = JUMP_VELOCITY;
+    }
+
+    let mut position = [bot.x, bot.y, bot.z];
+    match slide(world, &mut position, 1, body.velocity) {
+        Some(false) => body.on_ground = false,
+        Some(true) => {
+            body.on_ground = body.velocity < 0.0;
+            body.velocity = 0.0;
+        }
+        // Wait for the chunk below to arrive
+        None => body.velocity = 0.0,
+    }
+    body.velocity = (body.velocity - GRAVITY) * DRAG;
+
+    let speed = if body.sneaking {
+        SNEAK_SPEED
+    } else if body.sprinting {
+        SPRINT_SPEED
+    } else {
+        WALK_SPEED
+    };
+    let yaw = (body.yaw as f64).to_radians();
+    let blocked_x = slide(world, &mut position, 0, -yaw.sin() * speed) != Some(false);
+    let blocked_z = slide(world, &mut position, 2, yaw.cos() * speed) != Some(false);
+    // Keep pushing against the block while in the air, a jump clears it by the time it comes down
+    if (blocked_x || blocked_z) && body.on_ground {
+        let ahead = [
+            position[0] - yaw.sin() * speed,
+            position[1] + 1.0,
+            position[2] + yaw.cos() * speed,
+        ];
+        if collides(world, ahead) == Some(false) {
+            body.velocity = JUMP_VELOCITY;
+        } else {
+            body.yaw = (body.yaw + rng.gen_range(90.0..270.0)).rem_euclid(360.0);
+        }
+    }
+
+    bot.x = position[0];
+    bot.y = position[1];
+    bot.z = position[2];
+}
+
+/// Moves `position` by `delta` along `axis`, stopping at the first block in the way.
+///
+/// Returns whether a block was hit and `None` if the move reached an unloaded chunk. Landing
+/// puts the bot on top of the block.
+fn slide(world: &World, position: &mut [f64; 3], axis: usize, delta: f64) -> Option<bool> {
+    let steps = (delta.abs() / MAX_STEP).ceil().max(1.0);
+    for _ in 0..steps as u32 {
+        let mut next = *position;
+        next[axis] += delta / steps;
+        match collides(world, next)? {
+            false => *position = next,
+            true => {
+                if axis == 1 && delta < 0.0 {
+                    position[1] = next[1].floor() + 1.0;
+                }
+                return Some(true);
+            }
+        }
+    }
+    Some(false)
+}
+
+/// Whether a player standing at `position` overlaps a solid block, `None` if it reaches into
+/// an unloaded chunk
+fn collides(world: &World, [x, y, z]: [f64; 3]) -> Option<bool> {
+    let blocks = |from: f64, to: f64| from.floor() as i32..=to.ceil() as i32 - 1;
+    let mut solid = false;
+    for block_x in blocks(x - HALF_WIDTH, x + HALF_WIDTH) {
+        for block_z in blocks(z - HALF_WIDTH, z + HALF_WIDTH) {
+            for block_y in blocks(y, y + HEIGHT) {
+                solid |= world.is_solid(block_x, block_y, block_z)?;
+            }
+        }
+    }
+    Some(solid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    const STONE: u32 = 1;
+    const WATER: u32 = 80;
+    const SHORT_GRASS: u32 = 2005;
+
+    fn world() -> World {
+        let protocol = protocol::get(767).unwrap();
+        let mut world = World::new(protocol.air_states, protocol.non_colliding);
+        world.load_empty_chunk((0, 0));
+        world
+    }
+
+    #[test]
+    fn lands_on_solid_blocks() {
+        let mut world = world();
+        world.set_block(8, 63, 8, STONE);
+        let mut position = [8.5, 66.0, 8.5];
+        assert_eq!(slide(&world, &mut position, 1, -1.0), Some(false));
+        assert_eq!(position[1], 65.0);
+        assert_eq!(slide(&world, &mut position, 1, -3.0), Some(true));
+        assert_eq!(position, [8.5, 64.0, 8.5]);
+    }
+
+    #[test]
+    fn falls_through_water_and_grass() {
+        let mut world = world();
+        world.set_block(8, 63, 8, WATER);
+        world.set_block(8, 62, 8, SHORT_GRASS);
+        world.set_block(8, 60, 8, STONE);
+        let mut position = [8.5, 65.0, 8.5];
+        assert_eq!(slide(&world, &mut position, 1, -5.0), Some(true));
+        assert_eq!(position[1], 61.0);
+    }
+
+    #[test]
+    fn slides_along_walls() {
+        let mut world = world();
+        for z in 0..16 {
+            world.set_block(10, 64, z, STONE);
+            world.set_block(10, 65, z, STONE);
+        }
+        let mut position = [9.5, 64.0, 8.5];
+        assert_eq!(slide(&world, &mut position, 0, 0.5), Some(true));
+        assert_eq!(slide(&world, &mut position, 2, 0.25), Some(false));
+        assert_eq!(position, [9.5, 64.0, 8.75]);
+        // The chunk next to the wall hasn't been received
+        assert_eq!(slide(&world, &mut position, 2, 8.0), None);
+    }
+}
@@ -0,0 +1,453 @@
+use crate::metrics::{Histogram, Metrics, MetricsHub};
+use crate::ProtocolState;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+const MAX_REQUEST: usize = 8192;
+/// Connections that haven't sent their request and read the response by then are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bounds of the duration buckets, in seconds. The histograms are log-linear, so each
+/// bound is exposed as the edge of the histogram bucket it falls in, up to 12.5% higher.
+const BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+/// Keep alives are sent every 15 seconds by vanilla servers
+const INTERVAL_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 14.0, 15.0, 15.5, 16.0, 20.0, 30.0, 60.0];
+
+struct Connection {
+    stream: TcpStream,
+    accepted: Instant,
+    request: Vec<u8>,
+    response: Option<Vec<u8>>,
+    written: usize,
+}
+
+/// Binds the `/metrics` endpoint and serves it from its own thread
+pub fn start(address: SocketAddr, hub: Arc<MetricsHub>) -> io::Result<()> {
+    let mut listener = TcpListener::bind(address)?;
+    let poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+    println!("serving metrics on http://{}/metrics", address);
+
+    std::thread::spawn(move || serve(poll, listener, hub));
+    Ok(())
+}
+
+fn serve(mut poll: Poll, listener: TcpListener, hub: Arc<MetricsHub>) {
+    let mut events = Events::with_capacity(64);
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = 1;
+
+    loop {
+        if let Err(err) = poll.poll(&mut events, Some(IDLE_TIMEOUT / 10)) {
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            println!("metrics endpoint stopped: {}", err);
+            return;
+        }
+
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                loop {
+                    match listener.accept() {
+                        Ok((mut stream, _)) => {
+                            let token = Token(next_token);
+                            next_token += 1;
+                            if poll
+                                .registry()
+                                .register(
+                                    &mut stream,
+                                    token,
+                                    Interest::READABLE | Interest::WRITABLE,
+                                )
+                                .is_ok()
+                            {
+                                connections.insert(
+                                    token,
+                                    Connection {
+                                        stream,
+                                        accepted: Instant::now(),
+                                        request: Vec::new(),
+                                        response: None,
+                                        written: 0,
+                                    },
+                                );
+                            }
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            println!("could not accept metrics connection: {}", err);
+                            break;
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let token = event.token();
+            let done = match connections.get_mut(&token) {
+                Some(connection) => handle(connection, &hub),
+                None => false,
+            };
+            if done {
+                if let Some(mut connection) = connections.remove(&token) {
+                    let _ = poll.registry().deregister(&mut connection.stream);
+                }
+            }
+        }
+
+        // Scrapers and port scanners may leave a connection open without a full request
+        connections.retain(|_, connection| {
+            let idle = connection.accepted.elapsed() >= IDLE_TIMEOUT;
+            if idle {
+                let _ = poll.registry().deregister(&mut connection.stream);
+            }
+            !idle
+        });
+    }
+}
+
+/// Reads the request and writes the response, returns true once the connection can be closed
+fn handle(connection: &mut Connection, hub: &MetricsHub) -> bool {
+    if connection.response.is_none() {
+        let mut buf = [0u8; 1024];
+        loop {
+            match connection.stream.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(read) => {
+                    connection.request.extend_from_slice(&buf[..read]);
+                    if connection.request.len() > MAX_REQUEST {
+                        return true;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return true,
+            }
+        }
+
+        if !connection.request.windows(4).any(|w| w == b"\r\n\r\n") {
+            return false;
+        }
+        connection.response = Some(respond(&connection.request, hub));
+    }
+
+    let response = connection.response.as_ref().unwrap();
+    while connection.written < response.len() {
+        match connection.stream.write(&response[connection.written..]) {
+            Ok(written) => connection.written += written,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return false,
+            Err(_) => return true,
+        }
+    }
+    true
+}
+
+fn respond(request: &[u8], hub: &MetricsHub) -> Vec<u8> {
+    let request = String::from_utf8_lossy(request);
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    let (status, body) = if method != "GET" {
+        ("405 Method Not Allowed", String::new())
+    } else if path == "/metrics" || path.starts_with("/metrics?") {
+        ("200 OK", exposition(&hub.snapshot()))
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body.as_bytes());
+    response
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn histogram_seconds(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    buckets: &[f64],
+    histogram: &Histogram,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let mut edges: Vec<u64> = buckets
+        .iter()
+        .map(|bound| Histogram::bucket_edge((bound * 1_000_000.0) as u64))
+        .collect();
+    edges.dedup();
+    // Durations are recorded in whole microseconds, rounded down, so every value counted below
+    // the edge was shorter than it
+    for edge in edges {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"{}\"}} {}",
+            name,
+            edge as f64 / 1_000_000.0,
+            histogram.count_at_most(edge - 1)
+        );
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count());
+    let _ = writeln!(out, "{}_sum {}", name, histogram.sum() as f64 / 1_000_000.0);
+    let _ = writeln!(out, "{}_count {}", name, histogram.count());
+}
+
+/// Renders the metrics in the Prometheus text format
+pub fn exposition(metrics: &Metrics) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP mc_bot_connected Bots connected in each protocol state"
+    );
+    let _ = writeln!(out, "# TYPE mc_bot_connected gauge");
+    for state in [
+        ProtocolState::Status,
+        ProtocolState::Login,
+        ProtocolState::Config,
+        ProtocolState::Play,
+    ] {
+        let _ = writeln!(
+            out,
+            "mc_bot_connected{{state=\"{}\"}} {}",
+            format!("{:?}", state).to_lowercase(),
+            metrics.online[state.index()]
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP mc_bot_tracked_entities Entities tracked by the bots in the play state"
+    );
+    let _ = writeln!(out, "# TYPE mc_bot_tracked_entities gauge");
+    let _ = writeln!(out, "mc_bot_tracked_entities {}", metrics.entities);
+
+    let sent = metrics.total_out();
+    let received = metrics.total_in();
+    counter(
+        &mut out,
+        "mc_bot_packets_sent_total",
+        "Packets sent to the server",
+        sent.packets,
+    );
+    counter(
+        &mut out,
+        "mc_bot_packets_received_total",
+        "Packets received from the server",
+        received.packets,
+    );
+    counter(
+        &mut out,
+        "mc_bot_bytes_sent_total",
+        "Bytes sent to the server",
+        sent.bytes,
+    );
+    counter(
+        &mut out,
+        "mc_bot_bytes_received_total",
+        "Bytes received from the server",
+        received.bytes,
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP mc_bot_compression_bytes_saved_total Bytes saved by compressing packets"
+    );
+    let _ = writeln!(out, "# TYPE mc_bot_compression_bytes_saved_total counter");
+    let _ = writeln!(
+        out,
+        "mc_bot_compression_bytes_saved_total{{direction=\"in\"}} {}",
+        metrics.compression_in.saved()
+    );
+    let _ = writeln!(
+        out,
+        "mc_bot_compression_bytes_saved_total{{direction=\"out\"}} {}",
+        metrics.compression_out.saved()
+    );
+
+    counter(
+        &mut out,
+        "mc_bot_joined_total",
+        "Bots that reached the play state",
+        metrics.joined,
+    );
+    counter(
+        &mut out,
+        "mc_bot_connect_failures_total",
+        "Connections that were refused, failed or timed out before opening",
+        metrics.connect_failures,
+    );
+    counter(
+        &mut out,
+        "mc_bot_transfers_total",
+        "Transfers to another server that were followed",
+        metrics.transfers,
+    );
+    counter(
+        &mut out,
+        "mc_bot_reconnects_total",
+        "Connections reopened by bots that were kicked or lost their connection",
+        metrics.reconnects,
+    );
+    counter(
+        &mut out,
+        "mc_bot_chat_sent_total",
+        "Chat messages sent by the bots",
+        metrics.chat_sent,
+    );
+    counter(
+        &mut out,
+        "mc_bot_chat_probes_sent_total",
+        "Chat messages sent by the latency probe",
+        metrics.chat_probes_sent,
+    );
+    counter(
+        &mut out,
+        "mc_bot_chat_unreadable_total",
+        "Chat packets skipped because their text couldn't be read",
+        metrics.chat_unreadable,
+    );
+    counter(
+        &mut out,
+        "mc_bot_chat_received_total",
+        "Chat messages received by the bots, summed over the bots",
+        metrics.chat_received,
+    );
+    counter(
+        &mut out,
+        "mc_bot_commands_sent_total",
+        "Commands sent by the bots",
+        metrics.commands_sent,
+    );
+    counter(
+        &mut out,
+        "mc_bot_command_errors_total",
+        "Commands the server answered with an error",
+        metrics.command_errors,
+    );
+    counter(
+        &mut out,
+        "mc_bot_disconnected_total",
+        "Bots that disconnected",
+        metrics.disconnected,
+    );
+
+    histogram_seconds(
+        &mut out,
+        "mc_bot_tick_duration_seconds",
+        "Time a worker thread spent processing a tick",
+        BUCKETS,
+        &metrics.tick_duration,
+    );
+    histogram_seconds(
+        &mut out,
+        "mc_bot_status_ping_seconds",
+        "Round trip of the status ping",
+        BUCKETS,
+        &metrics.ping_rtt,
+    );
+    histogram_seconds(
+        &mut out,
+        "mc_bot_probe_rtt_seconds",
+        "Round trip of the ping probe sent in the play state",
+        BUCKETS,
+        &metrics.probe_rtt,
+    );
+    histogram_seconds(
+        &mut out,
+        "mc_bot_chat_latency_seconds",
+        "Time from sending a chat probe until another bot received it",
+        BUCKETS,
+        &metrics.chat_latency,
+    );
+    histogram_seconds(
+        &mut out,
+        "mc_bot_keep_alive_interval_seconds",
+        "Time between two keep alives sent to the same bot",
+        INTERVAL_BUCKETS,
+        &metrics.keep_alive_interval,
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP mc_bot_status_responses_total Status responses received, by whether they were valid JSON"
+    );
+    let _ = writeln!(out, "# TYPE mc_bot_status_responses_total counter");
+    let _ = writeln!(
+        out,
+        "mc_bot_status_responses_total{{result=\"ok\"}} {}",
+        metrics.status_ok
+    );
+    let _ = writeln!(
+        out,
+        "mc_bot_status_responses_total{{result=\"invalid\"}} {}",
+        metrics.status_invalid
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The `le` lines of a histogram as bound and count
+    fn buckets(out: &str, name: &str) -> Vec<(f64, u64)> {
+        let prefix = format!("{}_bucket{{le=\"", name);
+        out.lines()
+            .filter_map(|line| line.strip_prefix(&prefix))
+            .filter(|line| !line.starts_with("+Inf"))
+            .map(|line| {
+                let (bound, count) = line.split_once("\"} ").unwrap();
+                (bound.parse().unwrap(), count.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn histogram_buckets_count_every_value_below_their_bound() {
+        for bounds in [BUCKETS, INTERVAL_BUCKETS] {
+            let mut histogram = Histogram::new();
+            let mut values = Vec::new();
+            for bound in bounds {
+                let micros = (bound * 1_000_000.0) as u64;
+                values.extend([micros - 1, Histogram::bucket_edge(micros) - 1]);
+            }
+            values.iter().for_each(|value| histogram.record(*value));
+
+            let mut out = String::new();
+            histogram_seconds(&mut out, "test", "test", bounds, &histogram);
+            let lines = buckets(&out, "test");
+            assert!(!lines.is_empty());
+            for (bound, count) in lines {
+                let below = values
+                    .iter()
+                    .filter(|value| (**value as f64) < bound * 1_000_000.0)
+                    .count();
+                assert_eq!(count, below as u64, "le {}", bound);
+                assert!(bounds.iter().any(|nominal| *nominal <= bound));
+            }
+        }
+    }
+}
@@ -0,0 +1,764 @@
+use crate::packet_processors::PacketFramer;
+use crate::packet_utils::Buf;
+use crate::states::{login, status};
+use crate::{Address, Error, ProtocolState};
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Status responses can carry a base64 favicon, anything larger than this is refused
+const MAX_RESPONSE: u32 = 1 << 21;
+
+/// Packets the bots handle, their ids depend on the protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clientbound {
+    StatusResponse,
+    PongResponse,
+    Disconnect,
+    EncryptionRequest,
+    LoginSuccess,
+    SetCompression,
+    LoginPluginRequest,
+    CookieRequest,
+    FinishConfiguration,
+    KeepAlive,
+    Ping,
+    PingResponse,
+    AddResourcePack,
+    StoreCookie,
+    Transfer,
+    KnownPacks,
+    Login,
+    SynchronizePlayerPosition,
+    Respawn,
+    ChunkData,
+    ChunkBatchFinished,
+    UnloadChunk,
+    BlockUpdate,
+    SectionBlocksUpdate,
+    SpawnEntity,
+    RemoveEntities,
+    UpdateEntityPosition,
+    UpdateEntityPositionAndRotation,
+    UpdateEntityRotation,
+    TeleportEntity,
+    SetEntityMetadata,
+    SystemChat,
+    PlayerChat,
+    DisguisedChat,
+}
+
+/// Packets the bots send, their ids depend on the protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Serverbound {
+    LoginStart,
+    EncryptionResponse,
+    LoginPluginResponse,
+    LoginAcknowledged,
+    CookieResponse,
+    ClientInformation,
+    AcknowledgeFinishConfiguration,
+    KeepAlive,
+    Pong,
+    ResourcePackResponse,
+    KnownPacks,
+    ConfirmTeleportation,
+    ChatCommand,
+    ChatMessage,
+    ChunkBatchReceived,
+    SetPlayerPosition,
+    SetPlayerPositionAndRotation,
+    SetPlayerRotation,
+    SetPlayerOnGround,
+    PlayerCommand,
+    SetHeldItem,
+    SwingArm,
+    PingRequest,
+}
+
+/// Packet ids and field layouts of one protocol version.
+///
+/// The handshake and the status packets never changed, so they are written with fixed ids and
+/// can be used before the version is known.
+pub struct Protocol {
+    pub version: u32,
+    pub name: &'static str,
+    /// Text components are NBT instead of JSON in the configuration and play states
+    pub nbt_text: bool,
+    /// Resource packs are identified by a UUID
+    pub resource_pack_ids: bool,
+    /// Login Success ends with the strict error handling flag
+    pub strict_error_handling: bool,
+    /// Encryption Request says whether the client should authenticate
+    pub optional_authentication: bool,
+    /// Chat types are sent as their registry id plus one, or 0 followed by an inline definition
+    pub chat_type_holders: bool,
+    /// Chat Command only carries the command, signed commands have a packet of their own
+    pub unsigned_commands: bool,
+    /// Block states that are some kind of air, air itself is always 0
+    pub air_states: &'static [u32],
+    /// Other block states players walk through, like fluids, plants and torches
+    pub non_colliding: &'static [RangeInclusive<u32>],
+    /// Indexed by `ProtocolState::index`
+    clientbound: [&'static [(u8, Clientbound)]; 4],
+    serverbound: [&'static [(Serverbound, u8)]; 4],
+}
+
+impl Protocol {
+    pub fn clientbound(&self, state: ProtocolState, id: u32) -> Option<Clientbound> {
+        self.clientbound[state.index()]
+            .iter()
+            .find(|(packet_id, _)| *packet_id as u32 == id)
+            .map(|(_, packet)| *packet)
+    }
+
+    /// Packets are only written in answer to packets of the same version, so a missing id is a bug
+    pub fn serverbound(&self, state: ProtocolState, packet: Serverbound) -> u32 {
+        self.serverbound[state.index()]
+            .iter()
+            .find(|(serverbound, _)| *serverbound == packet)
+            .map(|(_, id)| *id as u32)
+            .unwrap_or_else(|| {
+                panic!(
+                    "{:?} doesn't exist in the {:?} state of protocol {}",
+                    packet, state, self.version
+                )
+            })
+    }
+}
+
+const STATUS_CLIENTBOUND: &[(u8, Clientbound)] = &[
+    (0x00, Clientbound::StatusResponse),
+    (0x01, Clientbound::PongResponse),
+];
+
+const LOGIN_CLIENTBOUND_764: &[(u8, Clientbound)] = &[
+    (0x00, Clientbound::Disconnect),
+    (0x01, Clientbound::EncryptionRequest),
+    (0x02, Clientbound::LoginSuccess),
+    (0x03, Clientbound::SetCompression),
+    (0x04, Clientbound::LoginPluginRequest),
+];
+
+const LOGIN_CLIENTBOUND_766: &[(u8, Clientbound)] = &[
+    (0x00, Clientbound::Disconnect),
+    (0x01, Clientbound::EncryptionRequest),
+    (0x02, Clientbound::LoginSuccess),
+    (0x03, Clientbound::SetCompression),
+    (0x04, Clientbound::LoginPluginRequest),
+    (0x05, Clientbound::CookieRequest),
+];
+
+const LOGIN_SERVERBOUND_764: &[(Serverbound, u8)] = &[
+    (Serverbound::LoginStart, 0x00),
+    (Serverbound::EncryptionResponse, 0x01),
+    (Serverbound::LoginPluginResponse, 0x02),
+    (Serverbound::LoginAcknowledged, 0x03),
+];
+
+const LOGIN_SERVERBOUND_766: &[(Serverbound, u8)] = &[
+    (Serverbound::LoginStart, 0x00),
+    (Serverbound::EncryptionResponse, 0x01),
+    (Serverbound::LoginPluginResponse, 0x02),
+    (Serverbound::LoginAcknowledged, 0x03),
+    (Serverbound::CookieResponse, 0x04),
+];
+
+const CONFIG_CLIENTBOUND_764: &[(u8, Clientbound)] = &[
+    (0x01, Clientbound::Disconnect),
+    (0x02, Clientbound::FinishConfiguration),
+    (0x03, Clientbound::KeepAlive),
+    (0x04, Clientbound::Ping),
+    (0x06, Clientbound::AddResourcePack),
+];
+
+const CONFIG_CLIENTBOUND_765: &[(u8, Clientbound)] = &[
+    (0x01, Clientbound::Disconnect),
+    (0x02, Clientbound::FinishConfiguration),
+    (0x03, Clientbound::KeepAlive),
+    (0x04, Clientbound::Ping),
+    (0x07, Clientbound::AddResourcePack),
+];
+
+const CONFIG_CLIENTBOUND_766: &[(u8, Clientbound)] = &[
+    (0x00, Clientbound::CookieRequest),
+    (0x02, Clientbound::Disconnect),
+    (0x03, Clientbound::FinishConfiguration),
+    (0x04, Clientbound::KeepAlive),
+    (0x05, Clientbound::Ping),
+    (0x09, Clientbound::AddResourcePack),
+    (0x0A, Clientbound::StoreCookie),
+    (0x0B, Clientbound::Transfer),
+    (0x0E, Clientbound::KnownPacks),
+];
+
+const CONFIG_SERVERBOUND_764: &[(Serverbound, u8)] = &[
+    (Serverbound::ClientInformation, 0x00),
+    (Serverbound::AcknowledgeFinishConfiguration, 0x02),
+    (Serverbound::KeepAlive, 0x03),
+    (Serverbound::Pong, 0x04),
+    (Serverbound::ResourcePackResponse, 0x05),
+];
+
+const CONFIG_SERVERBOUND_766: &[(Serverbound, u8)] = &[
+    (Serverbound::ClientInformation, 0x00),
+    (Serverbound::CookieResponse, 0x01),
+    (Serverbound::AcknowledgeFinishConfiguration, 0x03),
+    (Serverbound::KeepAlive, 0x04),
+    (Serverbound::Pong, 0x05),
+    (Serverbound::ResourcePackResponse, 0x06),
+    (Serverbound::KnownPacks, 0x07),
+];
+
+const PLAY_CLIENTBOUND_764: &[(u8, Clientbound)] = &[
+    (0x01, Clientbound::SpawnEntity),
+    (0x09, Clientbound::BlockUpdate),
+    (0x0C, Clientbound::ChunkBatchFinished),
+    (0x1B, Clientbound::Disconnect),
+    (0x1C, Clientbound::DisguisedChat),
+    (0x1F, Clientbound::UnloadChunk),
+    (0x24, Clientbound::KeepAlive),
+    (0x25, Clientbound::ChunkData),
+    (0x29, Clientbound::Login),
+    (0x2C, Clientbound::UpdateEntityPosition),
+    (0x2D, Clientbound::UpdateEntityPositionAndRotation),
+    (0x2E, Clientbound::UpdateEntityRotation),
+    (0x33, Clientbound::Ping),
+    (0x34, Clientbound::PingResponse),
+    (0x37, Clientbound::PlayerChat),
+    (0x3E, Clientbound::SynchronizePlayerPosition),
+    (0x40, Clientbound::RemoveEntities),
+    (0x43, Clientbound::Respawn),
+    (0x45, Clientbound::SectionBlocksUpdate),
+    (0x54, Clientbound::SetEntityMetadata),
+    (0x67, Clientbound::SystemChat),
+    (0x6B, Clientbound::TeleportEntity),
+];
+
+const PLAY_CLIENTBOUND_765: &[(u8, Clientbound)] = &[
+    (0x01, Clientbound::SpawnEntity),
+    (0x09, Clientbound::BlockUpdate),
+    (0x0C, Clientbound::ChunkBatchFinished),
+    (0x1B, Clientbound::Disconnect),
+    (0x1C, Clientbound::DisguisedChat),
+    (0x1F, Clientbound::UnloadChunk),
+    (0x24, Clientbound::KeepAlive),
+    (0x25, Clientbound::ChunkData),
+    (0x29, Clientbound::Login),
+    (0x2C, Clientbound::UpdateEntityPosition),
+    (0x2D, Clientbound::UpdateEntityPositionAndRotation),
+    (0x2E, Clientbound::UpdateEntityRotation),
+    (0x33, Clientbound::Ping),
+    (0x34, Clientbound::PingResponse),
+    (0x37, Clientbound::PlayerChat),
+    (0x3E, Clientbound::SynchronizePlayerPosition),
+    (0x40, Clientbound::RemoveEntities),
+    (0x45, Clientbound::Respawn),
+    (0x47, Clientbound::SectionBlocksUpdate),
+    (0x56, Clientbound::SetEntityMetadata),
+    (0x69, Clientbound::SystemChat),
+    (0x6D, Clientbound::TeleportEntity),
+];
+
+const PLAY_CLIENTBOUND_766: &[(u8, Clientbound)] = &[
+    (0x01, Clientbound::SpawnEntity),
+    (0x09, Clientbound::BlockUpdate),
+    (0x0C, Clientbound::ChunkBatchFinished),
+    (0x16, Clientbound::CookieRequest),
+    (0x1D, Clientbound::Disconnect),
+    (0x1E, Clientbound::DisguisedChat),
+    (0x21, Clientbound::UnloadChunk),
+    (0x26, Clientbound::KeepAlive),
+    (0x27, Clientbound::ChunkData),
+    (0x2B, Clientbound::Login),
+    (0x2E, Clientbound::UpdateEntityPosition),
+    (0x2F, Clientbound::UpdateEntityPositionAndRotation),
+    (0x30, Clientbound::UpdateEntityRotation),
+    (0x35, Clientbound::Ping),
+    (0x36, Clientbound::PingResponse),
+    (0x39, Clientbound::PlayerChat),
+    (0x40, Clientbound::SynchronizePlayerPosition),
+    (0x42, Clientbound::RemoveEntities),
+    (0x47, Clientbound::Respawn),
+    (0x49, Clientbound::SectionBlocksUpdate),
+    (0x58, Clientbound::SetEntityMetadata),
+    (0x6B, Clientbound::StoreCookie),
+    (0x6C, Clientbound::SystemChat),
+    (0x70, Clientbound::TeleportEntity),
+    (0x73, Clientbound::Transfer),
+];
+
+const PLAY_SERVERBOUND_764: &[(Serverbound, u8)] = &[
+    (Serverbound::ConfirmTeleportation, 0x00),
+    (Serverbound::ChatCommand, 0x04),
+    (Serverbound::ChatMessage, 0x05),
+    (Serverbound::ChunkBatchReceived, 0x07),
+    (Serverbound::KeepAlive, 0x14),
+    (Serverbound::SetPlayerPosition, 0x16),
+    (Serverbound::SetPlayerPositionAndRotation, 0x17),
+    (Serverbound::SetPlayerRotation, 0x18),
+    (Serverbound::SetPlayerOnGround, 0x19),
+    (Serverbound::PingRequest, 0x1D),
+    (Serverbound::PlayerCommand, 0x21),
+    (Serverbound::Pong, 0x23),
+    (Serverbound::SetHeldItem, 0x2B),
+    (Serverbound::SwingArm, 0x32),
+];
+
+const PLAY_SERVERBOUND_765: &[(Serverbound, u8)] = &[
+    (Serverbound::ConfirmTeleportation, 0x00),
+    (Serverbound::ChatCommand, 0x04),
+    (Serverbound::ChatMessage, 0x05),
+    (Serverbound::ChunkBatchReceived, 0x07),
+    (Serverbound::KeepAlive, 0x15),
+    (Serverbound::SetPlayerPosition, 0x17),
+    (Serverbound::SetPlayerPositionAndRotation, 0x18),
+    (Serverbound::SetPlayerRotation, 0x19),
+    (Serverbound::SetPlayerOnGround, 0x1A),
+    (Serverbound::PingRequest, 0x1E),
+    (Serverbound::PlayerCommand, 0x22),
+    (Serverbound::Pong, 0x24),
+    (Serverbound::SetHeldItem, 0x2C),
+    (Serverbound::SwingArm, 0x33),
+];
+
+const PLAY_SERVERBOUND_766: &[(Serverbound, u8)] = &[
+    (Serverbound::ConfirmTeleportation, 0x00),
+    (Serverbound::ChatCommand, 0x04),
+    (Serverbound::ChatMessage, 0x06),
+    (Serverbound::ChunkBatchReceived, 0x08),
+    (Serverbound::CookieResponse, 0x11),
+    (Serverbound::KeepAlive, 0x18),
+    (Serverbound::SetPlayerPosition, 0x1A),
+    (Serverbound::SetPlayerPositionAndRotation, 0x1B),
+    (Serverbound::SetPlayerRotation, 0x1C),
+    (Serverbound::SetPlayerOnGround, 0x1D),
+    (Serverbound::PingRequest, 0x21),
+    (Serverbound::PlayerCommand, 0x25),
+    (Serverbound::Pong, 0x27),
+    (Serverbound::SetHeldItem, 0x2F),
+    (Serverbound::SwingArm, 0x36),
+];
+
+/// Air, void air and cave air, the blocks added since 1.20.2 all come after them in the
+/// registry so their ids didn't change
+const AIR_STATES_764: &[u32] = &[0, 12817, 12818];
+
+/// Blocks without a collision box, counted from the block registry of 1.20.2. Like the air
+/// states, their ids are the same up to 1.21.1.
+const NON_COLLIDING_764: &[RangeInclusive<u32>] = &[
+    // Saplings and the mangrove propagule
+    25..=78,
+    // Water and lava
+    80..=111,
+    // Powered and detector rails
+    1944..=1991,
+    // Cobweb, short grass, fern, dead bush, seagrass and tall seagrass
+    2004..=2010,
+    // Flowers and mushrooms
+    2075..=2090,
+    // Torches, fire and soul fire
+    2355..=2872,
+    // Redstone wire
+    2978..=4273,
+    // Wheat
+    4278..=4285,
+    // Standing signs
+    4302..=4589,
+    // Rails
+    4662..=4681,
+    // Wall, hanging and wall hanging signs, levers and the stone pressure plate
+    4762..=5651,
+    // Wooden pressure plates
+    5716..=5733,
+    // Redstone torches and the stone button
+    5738..=5771,
+    // A single snow layer, thicker ones have a collision box
+    5772..=5772,
+    // Sugar cane
+    5799..=5814,
+];
+
+/// Protocol versions the bots can log in with
+pub static PROTOCOLS: &[Protocol] = &[
+    Protocol {
+        version: 764,
+        name: "1.20.2",
+        nbt_text: false,
+        resource_pack_ids: false,
+        strict_error_handling: false,
+        optional_authentication: false,
+        chat_type_holders: false,
+        unsigned_commands: false,
+        air_states: AIR_STATES_764,
+        non_colliding: NON_COLLIDING_764,
+        clientbound: [
+            STATUS_CLIENTBOUND,
+            LOGIN_CLIENTBOUND_764,
+            CONFIG_CLIENTBOUND_764,
+            PLAY_CLIENTBOUND_764,
+        ],
+        serverbound: [
+            &[],
+            LOGIN_SERVERBOUND_764,
+            CONFIG_SERVERBOUND_764,
+            PLAY_SERVERBOUND_764,
+        ],
+    },
+    Protocol {
+        version: 765,
+        name: "1.20.3-1.20.4",
+        nbt_text: true,
+        resource_pack_ids: true,
+        strict_error_handling: false,
+        optional_authentication: false,
+        chat_type_holders: false,
+        unsigned_commands: false,
+        air_states: AIR_STATES_764,
+        non_colliding: NON_COLLIDING_764,
+        clientbound: [
+            STATUS_CLIENTBOUND,
+            LOGIN_CLIENTBOUND_764,
+            CONFIG_CLIENTBOUND_765,
+            PLAY_CLIENTBOUND_765,
+        ],
+        serverbound: [
+            &[],
+            LOGIN_SERVERBOUND_764,
+            CONFIG_SERVERBOUND_764,
+            PLAY_SERVERBOUND_765,
+        ],
+    },
+    Protocol {
+        version: 766,
+        name: "1.20.5-1.20.6",
+        nbt_text: true,
+        resource_pack_ids: true,
+        strict_error_handling: true,
+        optional_authentication: true,
+        chat_type_holders: true,
+        unsigned_commands: true,
+        air_states: AIR_STATES_764,
+        non_colliding: NON_COLLIDING_764,
+        clientbound: [
+            STATUS_CLIENTBOUND,
+            LOGIN_CLIENTBOUND_766,
+            CONFIG_CLIENTBOUND_766,
+            PLAY_CLIENTBOUND_766,
+        ],
+        serverbound: [
+            &[],
+            LOGIN_SERVERBOUND_766,
+            CONFIG_SERVERBOUND_766,
+            PLAY_SERVERBOUND_766,
+        ],
+    },
+    Protocol {
+        version: 767,
+        name: "1.21-1.21.1",
+        nbt_text: true,
+        resource_pack_ids: true,
+        strict_error_handling: true,
+        optional_authentication: true,
+        chat_type_holders: true,
+        unsigned_commands: true,
+        air_states: AIR_STATES_764,
+        non_colliding: NON_COLLIDING_764,
+        clientbound: [
+            STATUS_CLIENTBOUND,
+            LOGIN_CLIENTBOUND_766,
+            CONFIG_CLIENTBOUND_766,
+            PLAY_CLIENTBOUND_766,
+        ],
+        serverbound: [
+            &[],
+            LOGIN_SERVERBOUND_766,
+            CONFIG_SERVERBOUND_766,
+            PLAY_SERVERBOUND_766,
+        ],
+    },
+];
+
+pub fn get(protocol_version: u32) -> Option<&'static Protocol> {
+    PROTOCOLS
+        .iter()
+        .find(|protocol| protocol.version == protocol_version)
+}
+
+pub fn is_supported(protocol_version: u32) -> bool {
+    get(protocol_version).is_some()
+}
+
+/// `764 (1.20.2), ...` for error messages
+pub fn supported_list() -> String {
+    PROTOCOLS
+        .iter()
+        .map(|protocol| format!("{} ({})", protocol.version, protocol.name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Why the bots can't log in with `protocol_version`, for error messages
+pub fn unsupported_reason(protocol_version: u32) -> String {
+    let oldest = &PROTOCOLS[0];
+    let newest = &PROTOCOLS[PROTOCOLS.len() - 1];
+    let reason = if protocol_version < oldest.version {
+        format!(
+            "protocols before {} ({}) have no configuration state, which the bots rely on",
+            oldest.version, oldest.name
+        )
+    } else if protocol_version > newest.version {
+        format!(
+            "protocols after {} ({}) changed packets the bots don't know yet",
+            newest.version, newest.name
+        )
+    } else {
+        "it isn't a release version".to_owned()
+    };
+    format!(
+        "protocol {} is not supported, {}; supported versions are {}",
+        protocol_version,
+        reason,
+        supported_list()
+    )
+}
+
+/// Queries the server's status and returns the protocol version it reports, failing if the
+/// bots can't log in with it
+pub fn detect(address: &Address, protocol_version: u32) -> Result<u32, Error> {
+    let response = query_status(address, protocol_version)
+        .map_err(|err| format!("could not query the server status: {}", err))?;
+    let status: serde_json::Value = serde_json::from_str(&response)
+        .map_err(|err| format!("invalid status response: {}", err))?;
+
+    let version = &status["version"];
+    let detected = version["protocol"]
+        .as_u64()
+        .ok_or("the status response has no version.protocol")? as u32;
+    let name = version["name"].as_str().unwrap_or("unknown version");
+
+    if !is_supported(detected) {
+        return Err(format!("the server runs {}: {}", name, unsupported_reason(detected)).into());
+    }
+
+    println!("server runs {} (protocol {})", name, detected);
+    Ok(detected)
+}
+
+/// Blocking handshake and status request, returns the status JSON
+fn query_status(address: &Address, protocol_version: u32) -> Result<String, Error> {
+    match address {
+        #[cfg(unix)]
+        Address::UNIX(path) => {
+            let stream = std::os::unix::net::UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            exchange(stream, protocol_version)
+        }
+        Address::TCP(address) => {
+            let stream = std::net::TcpStream::connect_timeout(address, TIMEOUT)?;
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            exchange(stream, protocol_version)
+        }
+    }
+}
+
+fn exchange(mut stream: impl Read + Write, protocol_version: u32) -> Result<String, Error> {
+    for packet in [
+        login::write_handshake_packet(protocol_version, "".to_string(), 0, 1),
+        status::write_status_request(),
+    ] {
+        let packet = PacketFramer::process_write(packet);
+        stream.write_all(
+            &packet.buffer[packet.get_reader_index() as usize..packet.get_writer_index() as usize],
+        )?;
+    }
+
+    // Read the length one byte at a time so nothing past the packet is consumed
+    let mut length = 0u32;
+    for i in 0..3 {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte)?;
+        length |= ((byte[0] & 0x7F) as u32) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    if length == 0 || length > MAX_RESPONSE {
+        return Err(format!("invalid status response length {}", length).into());
+    }
+
+    let mut packet = vec![0u8; length as usize];
+    stream.read_exact(&mut packet)?;
+    let mut packet = Buf::from_vec(packet);
+    packet.set_writer_index(length);
+
+    let packet_id = packet.read_var_u32().0;
+    if packet_id != 0x00 {
+        return Err(format!("expected a status response, got packet 0x{:02X}", packet_id).into());
+    }
+    Ok(packet.read_sized_string()?.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+
+    /// Answers with canned bytes and keeps what the client sent
+    struct Server {
+        response: Cursor<Vec<u8>>,
+        received: Vec<u8>,
+    }
+
+    impl Read for Server {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    impl Write for Server {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn server(response: Vec<u8>) -> Server {
+        Server {
+            response: Cursor::new(response),
+            received: Vec::new(),
+        }
+    }
+
+    fn status_response(packet_id: u32, json: &str) -> Vec<u8> {
+        let mut packet = Buf::new();
+        packet.write_var_u32(packet_id);
+        packet.write_sized_str(json);
+        let mut framed = PacketFramer::process_write(packet).buffer;
+        // Followed by bytes of the next packet, which are left alone
+        framed.extend_from_slice(&[1, 2, 3]);
+        framed
+    }
+
+    const STATES: [ProtocolState; 4] = [
+        ProtocolState::Status,
+        ProtocolState::Login,
+        ProtocolState::Config,
+        ProtocolState::Play,
+    ];
+
+    #[test]
+    fn packet_ids_are_unique() {
+        for protocol in PROTOCOLS {
+            for state in STATES {
+                let clientbound = protocol.clientbound[state.index()];
+                for (i, (id, packet)) in clientbound.iter().enumerate() {
+                    assert!(
+                        clientbound[..i]
+                            .iter()
+                            .all(|(other_id, other)| other_id != id && other != packet),
+                        "{:?} 0x{:02X} twice in {:?} of {}",
+                        packet,
+                        id,
+                        state,
+                        protocol.version
+                    );
+                    assert_eq!(protocol.clientbound(state, *id as u32), Some(*packet));
+                }
+                let serverbound = protocol.serverbound[state.index()];
+                for (i, (packet, id)) in serverbound.iter().enumerate() {
+                    assert!(
+                        serverbound[..i]
+                            .iter()
+                            .all(|(other, other_id)| other_id != id && other != packet),
+                        "{:?} 0x{:02X} twice in {:?} of {}",
+                        packet,
+                        id,
+                        state,
+                        protocol.version
+                    );
+                    assert_eq!(protocol.serverbound(state, *packet), *id as u32);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn packet_ids_follow_the_version() {
+        let respawn: Vec<_> = PROTOCOLS
+            .iter()
+            .map(|protocol| {
+                protocol
+                    .clientbound
+                    .iter()
+                    .flat_map(|packets| packets.iter())
+                    .find(|(_, packet)| *packet == Clientbound::Respawn)
+                    .map(|(id, _)| *id)
+            })
+            .collect();
+        assert_eq!(respawn, [Some(0x43), Some(0x45), Some(0x47), Some(0x47)]);
+        let play = ProtocolState::Play;
+        assert_eq!(
+            get(764).unwrap().clientbound(play, 0x45),
+            Some(Clientbound::SectionBlocksUpdate)
+        );
+        assert_eq!(get(764).unwrap().clientbound(play, 0xff), None);
+        assert_eq!(
+            get(765).unwrap().serverbound(play, Serverbound::KeepAlive),
+            0x15
+        );
+        // Status packets never changed
+        for protocol in PROTOCOLS {
+            assert_eq!(
+                protocol.clientbound(ProtocolState::Status, 0x00),
+                Some(Clientbound::StatusResponse)
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_versions_say_why() {
+        assert!(PROTOCOLS
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+        assert!(is_supported(764) && is_supported(767));
+        assert!(!is_supported(763) && !is_supported(768));
+        assert!(unsupported_reason(763).contains("no configuration state"));
+        assert!(unsupported_reason(769).contains("after 767 (1.21-1.21.1)"));
+        assert!(unsupported_reason(769).ends_with(&supported_list()));
+        assert!(supported_list().starts_with("764 (1.20.2), 765 (1.20.3-1.20.4)"));
+    }
+
+    #[test]
+    fn status_is_exchanged() {
+        let json = format!(
+            r#"{{"version":{{"name":"1.21","protocol":767}},"description":"{}"}}"#,
+            "a long motd ".repeat(50)
+        );
+        let mut server = server(status_response(0, &json));
+        assert_eq!(exchange(&mut server, 765).unwrap(), json);
+        assert_eq!(
+            server.response.position(),
+            server.response.get_ref().len() as u64 - 3
+        );
+        // Handshake 0x00 with the version as a VarInt
+        assert_eq!(server.received[1..4], [0x00, 0xfd, 0x05]);
+        // It asks for the status state and is followed by an empty status request
+        assert!(server.received.ends_with(&[1, 1, 0x00]));
+    }
+
+    #[test]
+    fn bad_status_responses_are_an_error() {
+        assert!(exchange(server(status_response(1, "{}")), 765).is_err());
+        assert!(exchange(server(vec![0]), 765).is_err());
+        assert!(exchange(server(vec![0xff, 0xff, 0x7f]), 765).is_err());
+        // Ends early
+        assert!(exchange(server(vec![10, 0, 8]), 765).is_err());
+        assert!(exchange(server(Vec::new()), 765).is_err());
+    }
+}
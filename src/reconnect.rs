@@ -0,0 +1,116 @@
+use crate::settings::Settings;
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Overrides the attempt limit for disconnect reasons containing `pattern`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Matched case insensitively against the kick message or disconnect reason
+    pub pattern: String,
+    /// Reconnect attempts allowed for matching reasons, 0 never reconnects
+    pub attempts: u32,
+}
+
+impl Rule {
+    fn matches(&self, reason: &str) -> bool {
+        reason.to_lowercase().contains(&self.pattern.to_lowercase())
+    }
+}
+
+/// How long a bot waits before its `attempt`th reconnect after disconnecting with `reason`,
+/// `None` once it has used up its attempts.
+///
+/// The delay doubles with every attempt up to `reconnect_max_delay`, and a random half of it
+/// is taken off so bots kicked together don't all come back in the same tick.
+pub fn delay(
+    settings: &Settings,
+    reason: &str,
+    attempt: u32,
+    rng: &mut StdRng,
+) -> Option<Duration> {
+    let attempts = settings
+        .reconnect_rules
+        .iter()
+        .find(|rule| rule.matches(reason))
+        .map_or(settings.reconnect_attempts, |rule| rule.attempts);
+    if attempt > attempts {
+        return None;
+    }
+
+    let backoff = settings.reconnect_delay * 2f64.powi(attempt.saturating_sub(1).min(30) as i32);
+    let backoff = backoff.min(settings.reconnect_max_delay);
+    Some(Duration::from_secs_f64(
+        backoff * (0.5 + rng.gen::<f64>() * 0.5),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn settings() -> Settings {
+        Settings {
+            reconnect_attempts: 8,
+            reconnect_delay: 1.0,
+            reconnect_max_delay: 30.0,
+            reconnect_rules: vec![Rule {
+                pattern: "Banned".to_owned(),
+                attempts: 0,
+            }],
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let settings = settings();
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            for (attempt, full) in [
+                (1, 1.0),
+                (2, 2.0),
+                (3, 4.0),
+                (5, 16.0),
+                (6, 30.0),
+                (8, 30.0),
+            ] {
+                let delay = delay(&settings, "timed out", attempt, &mut rng)
+                    .unwrap()
+                    .as_secs_f64();
+                assert!(
+                    delay >= full * 0.5 && delay <= full,
+                    "attempt {} waited {}s",
+                    attempt,
+                    delay
+                );
+            }
+        }
+        assert_eq!(delay(&settings, "timed out", 9, &mut rng), None);
+    }
+
+    #[test]
+    fn delays_are_jittered_by_the_seed() {
+        let settings = settings();
+        let delays = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (1..=8)
+                .map(|attempt| delay(&settings, "timed out", attempt, &mut rng).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays(7), delays(7));
+        assert_ne!(delays(7), delays(8));
+        assert_ne!(delays(7)[6], delays(7)[7]);
+    }
+
+    #[test]
+    fn rules_override_the_attempts() {
+        let settings = settings();
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(delay(&settings, "You are banned", 1, &mut rng), None);
+        assert!(delay(&settings, "Kicked for spamming", 1, &mut rng).is_some());
+    }
+}
@@ -0,0 +1,398 @@
+use crate::metrics::Metrics;
+use crate::settings::Settings;
+use crate::{Error, ProtocolState};
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One row of the time series, taken every second
+#[derive(Serialize)]
+pub struct Sample {
+    pub second: u64,
+    pub online: u32,
+    pub login: u32,
+    pub config: u32,
+    pub play: u32,
+    /// Entities tracked by the bots in the play state
+    pub entities: u64,
+    pub spawned: u64,
+    pub joined: u64,
+    pub join_failures: u64,
+    pub disconnected: u64,
+    pub packets_in_per_second: u64,
+    pub bytes_in_per_second: u64,
+    pub packets_out_per_second: u64,
+    pub bytes_out_per_second: u64,
+}
+
+#[derive(Serialize)]
+pub struct Reason {
+    pub reason: String,
+    pub count: u64,
+}
+
+#[derive(Serialize)]
+pub struct Totals {
+    pub spawned: u64,
+    pub joined: u64,
+    pub join_failures: u64,
+    pub connect_failures: u64,
+    pub disconnected: u64,
+    pub keep_alives: u64,
+    pub transfers: u64,
+    pub reconnects: u64,
+    pub chat_sent: u64,
+    pub chat_received: u64,
+    pub chat_probes_sent: u64,
+    pub chat_unreadable: u64,
+    pub commands_sent: u64,
+    pub command_errors: u64,
+    pub status_ok: u64,
+    pub status_invalid: u64,
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    pub connect_time_p50_us: u64,
+    pub connect_time_p99_us: u64,
+    pub join_latency_p50_us: u64,
+    pub join_latency_p99_us: u64,
+    pub ping_rtt_p50_us: u64,
+    pub ping_rtt_p99_us: u64,
+    pub keep_alive_interval_p50_us: u64,
+    pub keep_alive_interval_p99_us: u64,
+    pub keep_alive_interval_max_us: u64,
+    pub probe_rtt_p50_us: u64,
+    pub probe_rtt_p99_us: u64,
+    pub chat_latency_p50_us: u64,
+    pub chat_latency_p99_us: u64,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    started_at: u64,
+    duration_secs: f64,
+    settings: &'a Settings,
+    totals: Totals,
+    samples: &'a [Sample],
+    kick_reasons: Vec<Reason>,
+    disconnect_reasons: Vec<Reason>,
+    command_errors: Vec<Reason>,
+}
+
+/// Collects the time series of a run and writes it out once the run is over
+pub struct Recorder {
+    started_at: SystemTime,
+    samples: Vec<Sample>,
+    previous: Metrics,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder {
+            started_at: SystemTime::now(),
+            samples: Vec::new(),
+            previous: Metrics::default(),
+        }
+    }
+
+    pub fn sample(&mut self, second: u64, metrics: Metrics) {
+        let total_in = metrics.total_in();
+        let total_out = metrics.total_out();
+        let previous_in = self.previous.total_in();
+        let previous_out = self.previous.total_out();
+
+        self.samples.push(Sample {
+            second,
+            online: metrics.online(),
+            login: metrics.online[ProtocolState::Login.index()],
+            config: metrics.online[ProtocolState::Config.index()],
+            play: metrics.online[ProtocolState::Play.index()],
+            entities: metrics.entities,
+            spawned: metrics.spawned,
+            joined: metrics.joined,
+            join_failures: metrics.join_failures,
+            disconnected: metrics.disconnected,
+            packets_in_per_second: total_in.packets - previous_in.packets,
+            bytes_in_per_second: total_in.bytes - previous_in.bytes,
+            packets_out_per_second: total_out.packets - previous_out.packets,
+            bytes_out_per_second: total_out.bytes - previous_out.bytes,
+        });
+        self.previous = metrics;
+    }
+
+    /// Writes the report to `path`, as JSON or as CSV depending on the extension
+    pub fn write(
+        &self,
+        path: &Path,
+        settings: &Settings,
+        elapsed: Duration,
+        metrics: &Metrics,
+    ) -> Result<(), Error> {
+        let kick_reasons = sorted_reasons(&metrics.kick_reasons);
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => {
+                self.write_samples_csv(path)?;
+                write_reasons_csv(&sibling_path(path, "kicks"), &kick_reasons)?;
+                self.write_summary_csv(&sibling_path(path, "summary"), settings, elapsed, metrics)?;
+            }
+            _ => {
+                let report = Report {
+                    started_at: self.started_at_secs(),
+                    duration_secs: elapsed.as_secs_f64(),
+                    settings,
+                    totals: totals(metrics),
+                    samples: &self.samples,
+                    kick_reasons,
+                    disconnect_reasons: sorted_reasons(&metrics.disconnect_reasons),
+                    command_errors: sorted_reasons(&metrics.command_error_patterns),
+                };
+                let mut out = BufWriter::new(File::create(path)?);
+                serde_json::to_writer_pretty(&mut out, &report)?;
+                out.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn started_at_secs(&self) -> u64 {
+        self.started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    /// The run parameters and totals of the JSON report as `key,value` rows, nested settings
+    /// get dotted keys and lists are written as JSON
+    fn write_summary_csv(
+        &self,
+        path: &Path,
+        settings: &Settings,
+        elapsed: Duration,
+        metrics: &Metrics,
+    ) -> Result<(), Error> {
+        let mut rows = vec![
+            ("started_at".to_owned(), self.started_at_secs().to_string()),
+            (
+                "duration_secs".to_owned(),
+                elapsed.as_secs_f64().to_string(),
+            ),
+        ];
+        flatten("settings", &serde_json::to_value(settings)?, &mut rows);
+        flatten("totals", &serde_json::to_value(totals(metrics))?, &mut rows);
+
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "key,value")?;
+        for (key, value) in rows {
+            writeln!(out, "{},\"{}\"", key, value.replace('"', "\"\""))?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    fn write_samples_csv(&self, path: &Path) -> Result<(), Error> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(
+            out,
+            "second,online,login,config,play,entities,spawned,joined,join_failures,disconnected,\
+             packets_in_per_second,bytes_in_per_second,packets_out_per_second,bytes_out_per_second"
+        )?;
+        for s in &self.samples {
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                s.second,
+                s.online,
+                s.login,
+                s.config,
+                s.play,
+                s.entities,
+                s.spawned,
+                s.joined,
+                s.join_failures,
+                s.disconnected,
+                s.packets_in_per_second,
+                s.bytes_in_per_second,
+                s.packets_out_per_second,
+                s.bytes_out_per_second,
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `report.csv` keeps the time series, the kick reasons go to `report.kicks.csv` and the run
+/// parameters and totals to `report.summary.csv`
+fn sibling_path(path: &Path, name: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.{}.csv", stem, name))
+}
+
+fn flatten(key: &str, value: &Value, rows: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields {
+                flatten(&format!("{}.{}", key, name), value, rows);
+            }
+        }
+        Value::Null => rows.push((key.to_owned(), String::new())),
+        Value::String(text) => rows.push((key.to_owned(), text.clone())),
+        value => rows.push((key.to_owned(), value.to_string())),
+    }
+}
+
+fn write_reasons_csv(path: &Path, reasons: &[Reason]) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "count,reason")?;
+    for reason in reasons {
+        // Quote the reason and double any quotes inside it
+        writeln!(
+            out,
+            "{},\"{}\"",
+            reason.count,
+            reason.reason.replace('"', "\"\"")
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn sorted_reasons(reasons: &std::collections::HashMap<String, u64>) -> Vec<Reason> {
+    let mut reasons: Vec<_> = reasons
+        .iter()
+        .map(|(reason, count)| Reason {
+            reason: reason.clone(),
+            count: *count,
+        })
+        .collect();
+    reasons.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.reason.cmp(&b.reason)));
+    reasons
+}
+
+fn totals(metrics: &Metrics) -> Totals {
+    let total_in = metrics.total_in();
+    let total_out = metrics.total_out();
+    Totals {
+        spawned: metrics.spawned,
+        joined: metrics.joined,
+        join_failures: metrics.join_failures,
+        connect_failures: metrics.connect_failures,
+        disconnected: metrics.disconnected,
+        keep_alives: metrics.keep_alives,
+        transfers: metrics.transfers,
+        reconnects: metrics.reconnects,
+        chat_sent: metrics.chat_sent,
+        chat_received: metrics.chat_received,
+        chat_probes_sent: metrics.chat_probes_sent,
+        chat_unreadable: metrics.chat_unreadable,
+        commands_sent: metrics.commands_sent,
+        command_errors: metrics.command_errors,
+        status_ok: metrics.status_ok,
+        status_invalid: metrics.status_invalid,
+        packets_in: total_in.packets,
+        bytes_in: total_in.bytes,
+        packets_out: total_out.packets,
+        bytes_out: total_out.bytes,
+        connect_time_p50_us: metrics.connect_time.percentile(0.5),
+        connect_time_p99_us: metrics.connect_time.percentile(0.99),
+        join_latency_p50_us: metrics.join_latency.percentile(0.5),
+        join_latency_p99_us: metrics.join_latency.percentile(0.99),
+        ping_rtt_p50_us: metrics.ping_rtt.percentile(0.5),
+        ping_rtt_p99_us: metrics.ping_rtt.percentile(0.99),
+        keep_alive_interval_p50_us: metrics.keep_alive_interval.percentile(0.5),
+        keep_alive_interval_p99_us: metrics.keep_alive_interval.percentile(0.99),
+        keep_alive_interval_max_us: metrics.keep_alive_interval.max(),
+        probe_rtt_p50_us: metrics.probe_rtt.percentile(0.5),
+        probe_rtt_p99_us: metrics.probe_rtt.percentile(0.99),
+        chat_latency_p50_us: metrics.chat_latency.percentile(0.5),
+        chat_latency_p99_us: metrics.chat_latency.percentile(0.99),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Traffic;
+    use std::fs;
+
+    fn metrics(packets: u64, bytes: u64) -> Metrics {
+        let mut metrics = Metrics::default();
+        metrics
+            .packets_in
+            .insert((ProtocolState::Play, 0x24), Traffic { packets, bytes });
+        metrics
+    }
+
+    fn recorder() -> Recorder {
+        let mut recorder = Recorder::new();
+        recorder.sample(1, metrics(10, 100));
+        recorder.sample(2, metrics(25, 400));
+        recorder
+    }
+
+    #[test]
+    fn samples_count_the_traffic_of_their_second() {
+        let recorder = recorder();
+        let rates: Vec<_> = recorder
+            .samples
+            .iter()
+            .map(|sample| (sample.packets_in_per_second, sample.bytes_in_per_second))
+            .collect();
+        assert_eq!(rates, [(10, 100), (15, 300)]);
+    }
+
+    #[test]
+    fn reports_are_written_as_json_or_csv() {
+        let dir = std::env::temp_dir().join(format!("rust-mc-bot-report-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut metrics = metrics(25, 400);
+        metrics.kick_reasons.insert("said \"bye\"".to_owned(), 2);
+        metrics.kick_reasons.insert("full".to_owned(), 5);
+        metrics.kick_reasons.insert("banned".to_owned(), 2);
+        let settings = Settings::default();
+        let elapsed = Duration::from_secs(2);
+
+        let json = dir.join("report.json");
+        recorder()
+            .write(&json, &settings, elapsed, &metrics)
+            .unwrap();
+        let report: Value = serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+        assert_eq!(report["totals"]["packets_in"], 25);
+        assert_eq!(report["samples"][1]["bytes_in_per_second"], 300);
+        assert_eq!(report["kick_reasons"][0]["reason"], "full");
+        assert_eq!(report["kick_reasons"][1]["reason"], "banned");
+        assert_eq!(report["settings"]["view_distance"], 10);
+
+        let csv = dir.join("report.csv");
+        recorder()
+            .write(&csv, &settings, elapsed, &metrics)
+            .unwrap();
+        let samples = fs::read_to_string(&csv).unwrap();
+        assert_eq!(samples.lines().count(), 3);
+        assert!(samples.lines().nth(2).unwrap().starts_with("2,"));
+        assert_eq!(
+            fs::read_to_string(dir.join("report.kicks.csv")).unwrap(),
+            "count,reason\n5,\"full\"\n2,\"banned\"\n2,\"said \"\"bye\"\"\"\n"
+        );
+        let summary = fs::read_to_string(dir.join("report.summary.csv")).unwrap();
+        assert!(summary.contains("\ntotals.packets_in,\"25\"\n"));
+        assert!(summary.contains("\nsettings.server,\"\"\n"));
+        assert!(summary.contains("\nsettings.actions.chat.weight,\"1\"\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
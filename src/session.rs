@@ -0,0 +1,237 @@
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 8192;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JoinRequest {
+    access_token: String,
+    selected_profile: String,
+    server_id: String,
+}
+
+#[derive(Serialize)]
+struct Profile<'a> {
+    id: &'a str,
+    name: &'a str,
+    properties: [(); 0],
+}
+
+/// Tells the session server at `url` that the profile is joining the server identified by
+/// `server_hash`, this blocks the calling thread until the session server answers
+pub fn join(url: &str, access_token: &str, uuid: u128, server_hash: &str) -> Result<(), Error> {
+    let body = serde_json::to_string(&JoinRequest {
+        access_token: access_token.to_owned(),
+        selected_profile: format!("{:032x}", uuid),
+        server_id: server_hash.to_owned(),
+    })?;
+
+    let status = post(url, "/session/minecraft/join", &body)?;
+    if status != 204 && status != 200 {
+        return Err(format!("session server answered with status {}", status).into());
+    }
+    Ok(())
+}
+
+/// Minimal HTTP/1.1 client, only plain `http://` urls are supported
+fn post(url: &str, path: &str, body: &str) -> Result<u16, Error> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("unsupported session server url {}", url))?;
+    let (host, base) = match rest.split_once('/') {
+        Some((host, base)) => (host, format!("/{}", base.trim_end_matches('/'))),
+        None => (rest, String::new()),
+    };
+    let address = if host.contains(':') {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
+    };
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("no socket address found for {}", host))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        base,
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or("invalid response from the session server")?;
+    Ok(status)
+}
+
+/// Joins waiting for the server's `hasJoined` check, keyed by server hash
+type Joins = Arc<Mutex<HashMap<String, String>>>;
+
+/// Starts a Yggdrasil style session server that accepts every access token.
+///
+/// `join` remembers the profile under the server hash and `hasJoined` hands it back to the
+/// server once, with the name the server asks for. Returns the url to give to the bots.
+pub fn start_mock(address: SocketAddr) -> io::Result<String> {
+    let listener = TcpListener::bind(address)?;
+    let url = format!("http://{}", listener.local_addr()?);
+    println!("mock session server on {}", url);
+
+    let joins: Joins = Arc::default();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let joins = joins.clone();
+                    std::thread::spawn(move || handle(stream, &joins));
+                }
+                Err(err) => println!("could not accept session connection: {}", err),
+            }
+        }
+    });
+
+    Ok(url)
+}
+
+fn handle(mut stream: TcpStream, joins: &Joins) {
+    let _ = stream.set_read_timeout(Some(TIMEOUT));
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    // Read the head, then the body announced by Content-Length
+    let (head, mut body) = loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => request.extend_from_slice(&buf[..read]),
+        }
+        if request.len() > MAX_REQUEST {
+            return;
+        }
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..end]).into_owned();
+            break (head, request[end + 4..].to_vec());
+        }
+    };
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0usize)
+        .min(MAX_REQUEST);
+    while body.len() < length {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => body.extend_from_slice(&buf[..read]),
+        }
+    }
+
+    let mut parts = head.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let (status, body) = match (method, path) {
+        ("POST", "/session/minecraft/join") => match serde_json::from_slice::<JoinRequest>(&body) {
+            Ok(join) => {
+                let uuid = join.selected_profile.replace('-', "");
+                joins.lock().unwrap().insert(join.server_id, uuid);
+                ("204 No Content", String::new())
+            }
+            Err(_) => ("400 Bad Request", String::new()),
+        },
+        ("GET", "/session/minecraft/hasJoined") => {
+            let params: HashMap<&str, &str> = query
+                .split('&')
+                .filter_map(|param| param.split_once('='))
+                .collect();
+            let name = params.get("username").copied().unwrap_or_default();
+            let server_id = params.get("serverId").copied().unwrap_or_default();
+
+            match joins.lock().unwrap().remove(server_id) {
+                Some(uuid) => (
+                    "200 OK",
+                    serde_json::to_string(&Profile {
+                        id: &uuid,
+                        name,
+                        properties: [],
+                    })
+                    .unwrap_or_default(),
+                ),
+                None => ("204 No Content", String::new()),
+            }
+        }
+        ("GET", _) | ("POST", _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(url: &str, target: &str) -> String {
+        let mut stream = TcpStream::connect(url.strip_prefix("http://").unwrap()).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n",
+            target
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn mock_hands_out_each_join_once() {
+        let url = start_mock("127.0.0.1:0".parse().unwrap()).unwrap();
+        join(&url, "token", 0xabc, "-7c9d").unwrap();
+
+        let check = "/session/minecraft/hasJoined?username=Bot_1&serverId=-7c9d";
+        let response = get(&url, check);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let profile: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(profile["id"], format!("{:032x}", 0xabc));
+        assert_eq!(profile["name"], "Bot_1");
+        assert!(get(&url, check).starts_with("HTTP/1.1 204 "));
+
+        assert!(get(&url, "/unknown").starts_with("HTTP/1.1 404 "));
+        assert_eq!(
+            post(&url, "/session/minecraft/join", "not json").unwrap(),
+            400
+        );
+    }
+
+    #[test]
+    fn only_plain_http_is_supported() {
+        assert!(join("https://sessionserver.mojang.com", "token", 1, "hash").is_err());
+    }
+}
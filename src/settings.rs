@@ -0,0 +1,772 @@
+use crate::behaviour::{ActionMix, CommandScript, Group, Kind};
+use crate::phases::{self, Phase};
+use crate::reconnect::Rule;
+use crate::{protocol, Address, Error};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[cfg(unix)]
+const UDS_PREFIX: &str = "unix://";
+
+/// What every connection does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Log in and stay in the play state
+    Join,
+    /// Query the server list status and ping, then close the connection
+    Status,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Mode, Error> {
+        match mode {
+            "join" => Ok(Mode::Join),
+            "status" => Ok(Mode::Status),
+            _ => Err(format!("unknown mode {}, expected join or status", mode).into()),
+        }
+    }
+}
+
+/// Every knob of a run.
+///
+/// Values are layered: built in defaults, then the scenario file given with `--config`,
+/// then the command line flags.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// `ip:port`, or `unix:///path/to/socket` on unix
+    pub server: Option<String>,
+    pub mode: Mode,
+    /// Number of bots, or of status connections in status mode
+    pub count: u32,
+    /// Worker threads, defaults to the number of cpus
+    pub threads: Option<u32>,
+    /// This rate limits the join rate of the bots
+    /// Increasing it will cause the bots to join more quickly
+    pub joins_per_tick: f64,
+    /// Status connections opened per second across all threads, in status mode
+    pub status_rate: f64,
+    /// Bots disconnected per second across all threads once the run is stopped, and when a
+    /// phase steps down
+    pub leave_rate: f64,
+    /// Load profile played in order, the run ends after the last phase.
+    /// Without phases every bot joins once and the run lasts until they are all gone
+    pub phases: Vec<Phase>,
+    /// Seconds a connection may take to open before the bot gives up on it
+    pub connect_timeout: f64,
+    /// Reconnect bots that were kicked or lost their connection, under the same name
+    pub reconnect: bool,
+    /// Reconnects in a row a bot may try before giving up, reaching the play state resets it
+    pub reconnect_attempts: u32,
+    /// Seconds before the first reconnect, doubled for every further attempt
+    pub reconnect_delay: f64,
+    pub reconnect_max_delay: f64,
+    /// Attempt limits for particular disconnect reasons, the first matching rule wins
+    pub reconnect_rules: Vec<Rule>,
+    pub movement: bool,
+    pub messages: Vec<String>,
+    /// Behaviours the bots are spread over by weight, every bot runs `random` without groups
+    pub groups: Vec<Group>,
+    /// Commands run by the bots of the `commands` behaviour
+    pub commands: CommandScript,
+    /// Print every chat message the bots receive
+    pub log_chat: bool,
+    pub protocol_version: u32,
+    /// Query the server's status before the run and use the protocol version it reports
+    pub detect_protocol: bool,
+    pub view_distance: u8,
+    /// Every bot performs a random action once every `action_tick` ticks
+    pub action_tick: u32,
+    /// Weights and timers of the actions of the `random` behaviour
+    pub actions: ActionMix,
+    /// Seeds the random choices of every bot, a random seed is picked when missing
+    pub seed: Option<u64>,
+    /// Seconds between the ping probes every bot in the play state sends, 0 disables them
+    pub ping_probe_interval: f64,
+    /// Seconds between the chat messages every probing bot sends to measure chat latency, 0
+    /// disables them
+    pub chat_probe_interval: f64,
+    /// Bots with an index below this send the chat probes, the others only receive them
+    pub chat_probe_senders: u32,
+    /// Seconds between summary tables, 0 only prints the final report
+    pub report_interval: u64,
+    /// Where to write the run report, as JSON or CSV depending on the extension
+    pub report_file: Option<PathBuf>,
+    /// Serve Prometheus metrics on `http://<address>/metrics`
+    pub metrics_address: Option<SocketAddr>,
+    /// Session server the bots join through when the server is in online mode,
+    /// e.g. `http://127.0.0.1:8652`
+    pub session_server: Option<String>,
+    /// Start a mock session server on this address, the bots use it unless
+    /// `session_server` is set
+    pub mock_session_server: Option<SocketAddr>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            server: None,
+            mode: Mode::Join,
+            count: 0,
+            threads: None,
+            joins_per_tick: 5.0,
+            status_rate: 100.0,
+            leave_rate: 50.0,
+            phases: Vec::new(),
+            connect_timeout: 10.0,
+            reconnect: false,
+            reconnect_attempts: 5,
+            reconnect_delay: 1.0,
+            reconnect_max_delay: 30.0,
+            reconnect_rules: Vec::new(),
+            movement: true,
+            messages: vec![
+                "This is a chat message!".to_owned(),
+                "Wow".to_owned(),
+                "Server = on?".to_owned(),
+            ],
+            groups: Vec::new(),
+            commands: CommandScript::default(),
+            log_chat: false,
+            protocol_version: 767,
+            detect_protocol: false,
+            view_distance: 10,
+            action_tick: 4,
+            actions: ActionMix::default(),
+            seed: None,
+            ping_probe_interval: 0.0,
+            chat_probe_interval: 0.0,
+            chat_probe_senders: 1,
+            report_interval: 10,
+            report_file: None,
+            metrics_address: None,
+            session_server: None,
+            mock_session_server: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads a scenario file, the format is picked from the extension (`.toml` or `.json`)
+    pub fn from_file(path: &Path) -> Result<Settings, Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+
+        let settings = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&text)
+                .map_err(|err| format!("invalid scenario {}: {}", path.display(), err))?,
+            Some("json") => serde_json::from_str(&text)
+                .map_err(|err| format!("invalid scenario {}: {}", path.display(), err))?,
+            _ => {
+                return Err(format!(
+                    "unknown scenario format {}, expected a .toml or .json file",
+                    path.display()
+                )
+                .into())
+            }
+        };
+
+        Ok(settings)
+    }
+
+    /// Builds the settings from the program arguments, `None` means help was requested
+    pub fn from_args(args: &[String]) -> Result<Option<Settings>, Error> {
+        let mut flags = Vec::new();
+        let mut positional = Vec::new();
+        let mut config = None;
+
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            }
+
+            if !arg.starts_with('-') || arg.len() == 1 {
+                positional.push(arg.as_str());
+                continue;
+            }
+
+            // Support both `--flag value` and `--flag=value`
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_owned())),
+                None => (arg.as_str(), None),
+            };
+
+            if takes_value(flag) {
+                let value = match inline {
+                    Some(value) => value,
+                    None => iter
+                        .next()
+                        .ok_or_else(|| format!("{} requires a value", flag))?
+                        .to_owned(),
+                };
+
+                if flag == "-c" || flag == "--config" {
+                    config = Some(value);
+                } else {
+                    flags.push((flag, Some(value)));
+                }
+            } else if inline.is_some() {
+                return Err(format!("{} does not take a value", flag).into());
+            } else {
+                flags.push((flag, None));
+            }
+        }
+
+        let mut settings = match config {
+            Some(path) => Settings::from_file(Path::new(&path))?,
+            None => Settings::default(),
+        };
+
+        // Positional arguments are kept for compatibility with `<ip:port or path> <count> [threads]`
+        if positional.len() > 3 {
+            return Err(format!("unexpected argument {}", positional[3]).into());
+        }
+        if let Some(server) = positional.first() {
+            settings.server = Some(server.to_string());
+        }
+        if let Some(count) = positional.get(1) {
+            settings.count = parse_value("count", count)?;
+        }
+        if let Some(threads) = positional.get(2) {
+            settings.threads = Some(parse_value("threads", threads)?);
+        }
+
+        let mut messages_replaced = false;
+        let mut phases_replaced = false;
+        let mut groups_replaced = false;
+        let mut join_commands_replaced = false;
+        let mut timer_commands_replaced = false;
+        for (flag, value) in flags {
+            let value = value.as_deref().unwrap_or_default();
+            match flag {
+                "-s" | "--server" => settings.server = Some(value.to_owned()),
+                "-m" | "--mode" => settings.mode = value.parse()?,
+                "-n" | "--count" => settings.count = parse_value(flag, value)?,
+                "-t" | "--threads" => settings.threads = Some(parse_value(flag, value)?),
+                "--joins-per-tick" => settings.joins_per_tick = parse_value(flag, value)?,
+                "--status-rate" => settings.status_rate = parse_value(flag, value)?,
+                "--leave-rate" => settings.leave_rate = parse_value(flag, value)?,
+                "--phase" => {
+                    // Phases given on the command line replace the scenario's
+                    if !phases_replaced {
+                        settings.phases.clear();
+                        phases_replaced = true;
+                    }
+                    settings.phases.push(value.parse()?);
+                }
+                "--connect-timeout" => settings.connect_timeout = parse_value(flag, value)?,
+                "--reconnect" => settings.reconnect = true,
+                "--no-reconnect" => settings.reconnect = false,
+                "--reconnect-attempts" => settings.reconnect_attempts = parse_value(flag, value)?,
+                "--reconnect-delay" => settings.reconnect_delay = parse_value(flag, value)?,
+                "--reconnect-max-delay" => settings.reconnect_max_delay = parse_value(flag, value)?,
+                "--no-reconnect-on" => settings.reconnect_rules.push(Rule {
+                    pattern: value.to_owned(),
+                    attempts: 0,
+                }),
+                "--move" => settings.movement = true,
+                "--no-move" => settings.movement = false,
+                "--message" => {
+                    // Messages given on the command line replace the scenario's
+                    if !messages_replaced {
+                        settings.messages.clear();
+                        messages_replaced = true;
+                    }
+                    settings.messages.push(value.to_owned());
+                }
+                "--behaviour" => {
+                    // Groups given on the command line replace the scenario's
+                    if !groups_replaced {
+                        settings.groups.clear();
+                        groups_replaced = true;
+                    }
+                    settings.groups.push(value.parse()?);
+                }
+                "--join-command" => {
+                    // Commands given on the command line replace the scenario's
+                    if !join_commands_replaced {
+                        settings.commands.on_join.clear();
+                        join_commands_replaced = true;
+                    }
+                    settings.commands.on_join.push(value.to_owned());
+                }
+                "--timer-command" => {
+                    if !timer_commands_replaced {
+                        settings.commands.on_timer.clear();
+                        timer_commands_replaced = true;
+                    }
+                    settings.commands.on_timer.push(value.to_owned());
+                }
+                "--command-every" => settings.commands.every = parse_value(flag, value)?,
+                "--log-chat" => settings.log_chat = true,
+                "--no-log-chat" => settings.log_chat = false,
+                "--protocol-version" => settings.protocol_version = parse_value(flag, value)?,
+                "--detect-protocol" => settings.detect_protocol = true,
+                "--no-detect-protocol" => settings.detect_protocol = false,
+                "--view-distance" => settings.view_distance = parse_value(flag, value)?,
+                "--action-tick" => settings.action_tick = parse_value(flag, value)?,
+                "--action" => settings.actions.set(value)?,
+                "--random-phase" => settings.actions.random_phase = true,
+                "--no-random-phase" => settings.actions.random_phase = false,
+                "--seed" => settings.seed = Some(parse_value(flag, value)?),
+                "--ping-probe" => settings.ping_probe_interval = parse_value(flag, value)?,
+                "--chat-probe" => settings.chat_probe_interval = parse_value(flag, value)?,
+                "--chat-probe-senders" => settings.chat_probe_senders = parse_value(flag, value)?,
+                "--report-interval" => settings.report_interval = parse_value(flag, value)?,
+                "--report-file" => settings.report_file = Some(PathBuf::from(value)),
+                "--metrics-address" => settings.metrics_address = Some(parse_value(flag, value)?),
+                "--session-server" => settings.session_server = Some(value.to_owned()),
+                "--mock-session-server" => {
+                    settings.mock_session_server = Some(parse_value(flag, value)?)
+                }
+                _ => return Err(format!("unknown option {}", flag).into()),
+            }
+        }
+
+        // A profile without a count gets as many bots as its busiest phase
+        if settings.count == 0 {
+            settings.count = phases::validate(&settings.phases)?;
+        }
+
+        settings.validate()?;
+        Ok(Some(settings))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.server.is_none() {
+            return Err("no server address given".into());
+        }
+        if self.threads == Some(0) {
+            return Err("threads must be at least 1".into());
+        }
+        if !self.joins_per_tick.is_finite() || self.joins_per_tick <= 0.0 {
+            return Err(format!(
+                "joins per tick must be a positive number, got {}",
+                self.joins_per_tick
+            )
+            .into());
+        }
+        if !self.status_rate.is_finite() || self.status_rate <= 0.0 {
+            return Err(format!(
+                "status rate must be a positive number, got {}",
+                self.status_rate
+            )
+            .into());
+        }
+        if !self.leave_rate.is_finite() || self.leave_rate <= 0.0 {
+            return Err(format!(
+                "leave rate must be a positive number, got {}",
+                self.leave_rate
+            )
+            .into());
+        }
+        if !self.phases.is_empty() {
+            if self.mode != Mode::Join {
+                return Err("phases are only supported in join mode".into());
+            }
+            let peak = phases::validate(&self.phases)?;
+            if peak > self.count {
+                return Err(format!(
+                    "the phases need {} bots but the count is {}",
+                    peak, self.count
+                )
+                .into());
+            }
+        }
+        if !self.connect_timeout.is_finite() || self.connect_timeout <= 0.0 {
+            return Err(format!(
+                "connect timeout must be a positive number of seconds, got {}",
+                self.connect_timeout
+            )
+            .into());
+        }
+        if self.reconnect && self.mode != Mode::Join {
+            return Err("reconnecting is only supported in join mode".into());
+        }
+        if !self.reconnect_delay.is_finite() || self.reconnect_delay <= 0.0 {
+            return Err(format!(
+                "reconnect delay must be a positive number, got {}",
+                self.reconnect_delay
+            )
+            .into());
+        }
+        if !self.reconnect_max_delay.is_finite() || self.reconnect_max_delay < self.reconnect_delay
+        {
+            return Err(format!(
+                "reconnect max delay must be at least the reconnect delay, got {}",
+                self.reconnect_max_delay
+            )
+            .into());
+        }
+        if self
+            .reconnect_rules
+            .iter()
+            .any(|rule| rule.pattern.is_empty())
+        {
+            return Err("reconnect rule patterns can't be empty".into());
+        }
+        if !self.groups.is_empty() && self.groups.iter().all(|group| group.weight == 0) {
+            return Err("at least one behaviour group needs a weight above 0".into());
+        }
+        self.commands.validate()?;
+        let runs_commands = self
+            .groups
+            .iter()
+            .any(|group| group.behaviour == Kind::Commands && group.weight > 0);
+        if runs_commands && self.commands.on_join.is_empty() && self.commands.on_timer.is_empty() {
+            return Err("the commands behaviour needs at least one command".into());
+        }
+        if self.messages.is_empty() {
+            return Err("at least one chat message is required".into());
+        }
+        if !self.detect_protocol && !protocol::is_supported(self.protocol_version) {
+            return Err(protocol::unsupported_reason(self.protocol_version).into());
+        }
+        if self.view_distance < 2 {
+            return Err(format!(
+                "view distance must be at least 2, got {}",
+                self.view_distance
+            )
+            .into());
+        }
+        if self.action_tick == 0 {
+            return Err("action tick must be at least 1".into());
+        }
+        self.actions.validate()?;
+        if !self.ping_probe_interval.is_finite() || self.ping_probe_interval < 0.0 {
+            return Err(format!(
+                "ping probe interval must be 0 or more seconds, got {}",
+                self.ping_probe_interval
+            )
+            .into());
+        }
+        if !self.chat_probe_interval.is_finite() || self.chat_probe_interval < 0.0 {
+            return Err(format!(
+                "chat probe interval must be 0 or more seconds, got {}",
+                self.chat_probe_interval
+            )
+            .into());
+        }
+        if self.chat_probe_interval > 0.0 && self.chat_probe_senders == 0 {
+            return Err("the chat probe needs at least 1 sender".into());
+        }
+        if let Some(report_file) = &self.report_file {
+            match report_file.extension().and_then(|ext| ext.to_str()) {
+                Some("json") | Some("csv") => {}
+                _ => {
+                    return Err(format!(
+                        "unknown report format {}, expected a .json or .csv file",
+                        report_file.display()
+                    )
+                    .into())
+                }
+            }
+        }
+        if let Some(url) = &self.session_server {
+            if !url.starts_with("http://") {
+                return Err(format!(
+                    "unsupported session server url {}, only http:// is supported",
+                    url
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn threads(&self) -> u32 {
+        self.threads
+            .unwrap_or_else(|| 1.max(num_cpus::get()) as u32)
+    }
+
+    /// Resolves the server address, IPv6 addresses with a port are written as `[::1]:25565`
+    pub fn address(&self) -> Result<Address, Error> {
+        let server = self.server.as_deref().ok_or("no server address given")?;
+
+        #[cfg(unix)]
+        if let Some(unix_socket) = server.strip_prefix(UDS_PREFIX) {
+            return Ok(Address::UNIX(PathBuf::from(unix_socket)));
+        }
+
+        if let Ok(address) = server.parse::<SocketAddr>() {
+            return Ok(Address::TCP(address));
+        }
+        let bare = server.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = bare.parse::<IpAddr>() {
+            return Ok(Address::TCP(SocketAddr::new(ip, 25565)));
+        }
+
+        let (ip, port) = match server.rsplit_once(':') {
+            Some((ip, port)) => (ip, parse_value("port", port)?),
+            None => (server, 25565u16),
+        };
+
+        let address = (ip, port)
+            .to_socket_addrs()
+            .map_err(|err| format!("could not resolve {}: {}", server, err))?
+            .next()
+            .ok_or_else(|| format!("no socket address found for {}", server))?;
+
+        Ok(Address::TCP(address))
+    }
+}
+
+fn takes_value(flag: &str) -> bool {
+    !matches!(
+        flag,
+        "--move"
+            | "--no-move"
+            | "--detect-protocol"
+            | "--no-detect-protocol"
+            | "--reconnect"
+            | "--no-reconnect"
+            | "--random-phase"
+            | "--no-random-phase"
+            | "--log-chat"
+            | "--no-log-chat"
+    )
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value.parse().map_err(|_| {
+        format!(
+            "invalid value for {}: {}",
+            name.trim_start_matches('-'),
+            value
+        )
+        .into()
+    })
+}
+
+pub fn print_usage(name: &str) {
+    #[cfg(unix)]
+    println!(
+        "usage: {} [options] [<ip:port or path> <count> [threads]]",
+        name
+    );
+    #[cfg(not(unix))]
+    println!("usage: {} [options] [<ip:port> <count> [threads]]", name);
+    println!();
+    println!("options:");
+    println!("  -c, --config <file>           scenario file (.toml or .json), flags override it");
+    println!("  -s, --server <address>        server to connect to");
+    println!(
+        "  -m, --mode <join|status>      log in, or only query the status and ping (default: join)"
+    );
+    println!("  -n, --count <count>           number of bots, or of status connections");
+    println!("  -t, --threads <threads>       worker threads (default: number of cpus)");
+    println!(
+        "      --joins-per-tick <rate>   average joins per tick across all threads (default: 5)"
+    );
+    println!("      --status-rate <conn/s>    status connections per second in status mode (default: 100)");
+    println!(
+        "      --leave-rate <bots/s>     bots disconnected per second when stopping (default: 50)"
+    );
+    println!("      --phase <phase>           ramp:<bots>:<secs>, hold:<secs> or step:<bots>, may be repeated");
+    println!("      --connect-timeout <secs>  seconds before a connect that hasn't opened fails (default: 10)");
+    println!(
+        "      --reconnect, --no-reconnect  reconnect kicked or disconnected bots (default: off)"
+    );
+    println!("      --reconnect-attempts <n>  reconnects in a row before giving up (default: 5)");
+    println!("      --reconnect-delay <secs>  delay before the first reconnect, doubled after (default: 1)");
+    println!("      --reconnect-max-delay <secs>  longest delay between reconnects (default: 30)");
+    println!("      --no-reconnect-on <text>  never reconnect when the reason contains <text>, may be repeated");
+    println!("      --move, --no-move         whether the bots move around (default: move)");
+    println!("      --message <text>          chat message to send, may be repeated");
+    println!("      --behaviour <name[:weight]>  random, idle or commands, may be repeated to mix them (default: random)");
+    println!("      --join-command <cmd>      command the commands behaviour runs once spawned, may be repeated");
+    println!("      --timer-command <cmd>     command the commands behaviour runs on its timer, may be repeated");
+    println!("      --command-every <ticks>   ticks between the timer commands (default: 200)");
+    println!(
+        "      --log-chat, --no-log-chat  print the chat messages the bots receive (default: off)"
+    );
+    println!("      --protocol-version <ver>  protocol version to log in with (default: 767)");
+    println!("      --detect-protocol, --no-detect-protocol  use the protocol version from the server's status (default: off)");
+    println!("      --view-distance <chunks>  view distance sent to the server (default: 10)");
+    println!("      --action-tick <ticks>     ticks between random actions (default: 4)");
+    println!("      --action <name:weight[:every]>  weight of move, chat, swing, sneak, sprint or held_item, with every it runs on its own timer");
+    println!("      --random-phase, --no-random-phase  start every bot's action timers at a random tick (default: off)");
+    println!("      --seed <number>           seed for the bots' random choices (default: random)");
+    println!("      --ping-probe <secs>       seconds between ping probes in the play state (default: off)");
+    println!("      --chat-probe <secs>       seconds between chat latency probes in the play state (default: off)");
+    println!("      --chat-probe-senders <bots>  number of bots sending chat probes (default: 1)");
+    println!(
+        "      --report-file <file>      write a run report (.json or .csv) when the run ends"
+    );
+    println!("      --report-interval <secs>  seconds between summary tables, 0 to disable (default: 10)");
+    println!("      --metrics-address <addr>  serve Prometheus metrics on http://<addr>/metrics");
+    println!(
+        "      --session-server <url>    session server to join through for online mode servers"
+    );
+    println!(
+        "      --mock-session-server <addr>  start a mock session server on <addr> and use it"
+    );
+    println!("  -h, --help                    print this message");
+    println!();
+    println!(
+        "supported protocol versions: {}, 1.20.1 and older and 1.21.2 and newer are not",
+        protocol::supported_list()
+    );
+    println!();
+    println!("example: {} localhost:25565 500", name);
+    #[cfg(unix)]
+    println!("example: {} unix:///path/to/socket 500", name);
+    println!("example: {} --config scenario.toml --count 200", name);
+    println!(
+        "example: {} localhost:25565 --phase ramp:500:60 --phase hold:300 --phase ramp:0:30",
+        name
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn address(server: &str) -> SocketAddr {
+        let settings = Settings {
+            server: Some(server.to_owned()),
+            ..Settings::default()
+        };
+        match settings.address().unwrap() {
+            Address::TCP(address) => address,
+            #[cfg(unix)]
+            Address::UNIX(path) => panic!("{} is a unix socket {:?}", server, path),
+        }
+    }
+
+    #[test]
+    fn addresses_take_ipv6_and_a_default_port() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let localhost_v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_eq!(
+            address("127.0.0.1:25599"),
+            SocketAddr::new(localhost, 25599)
+        );
+        assert_eq!(address("127.0.0.1"), SocketAddr::new(localhost, 25565));
+        assert_eq!(address("[::1]:25599"), SocketAddr::new(localhost_v6, 25599));
+        assert_eq!(address("[::1]"), SocketAddr::new(localhost_v6, 25565));
+        assert_eq!(address("::1"), SocketAddr::new(localhost_v6, 25565));
+        assert_eq!(address("localhost:25599").port(), 25599);
+    }
+
+    /// Writes a scenario file that only this test reads
+    fn scenario(name: &str, text: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rust-mc-bot-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn parse(args: &[&str]) -> Result<Option<Settings>, Error> {
+        let args: Vec<String> = std::iter::once("rust-mc-bot")
+            .chain(args.iter().copied())
+            .map(str::to_owned)
+            .collect();
+        Settings::from_args(&args)
+    }
+
+    fn from_args(args: &[&str]) -> Settings {
+        parse(args).unwrap().unwrap()
+    }
+
+    #[test]
+    fn scenario_positionals_and_flags_are_layered() {
+        let defaults = Settings::default();
+        let path = scenario(
+            "layers",
+            "server = \"127.0.0.1:25565\"\ncount = 5\nthreads = 2\njoins_per_tick = 1.5\n\
+             messages = [\"from the scenario\"]\n[actions]\nchat = { weight = 3 }\n\
+             swing = { weight = 0, every = 20 }\n",
+        );
+        let config = path.to_str().unwrap();
+
+        let settings = from_args(&["--config", config]);
+        assert_eq!(settings.server.as_deref(), Some("127.0.0.1:25565"));
+        assert_eq!((settings.count, settings.threads), (5, Some(2)));
+        assert_eq!(settings.joins_per_tick, 1.5);
+        assert_eq!(settings.messages, ["from the scenario"]);
+        // Everything the scenario leaves out keeps its default
+        assert_eq!(settings.view_distance, defaults.view_distance);
+        assert_eq!(settings.actions.movement, defaults.actions.movement);
+        assert_eq!(settings.actions.chat.weight, 3);
+
+        let settings = from_args(&["127.0.0.1:25599", "7", "--config", config]);
+        assert_eq!(settings.server.as_deref(), Some("127.0.0.1:25599"));
+        assert_eq!((settings.count, settings.threads), (7, Some(2)));
+
+        // Flags win over positional arguments wherever they are
+        let settings = from_args(&[
+            "-n",
+            "9",
+            "--config",
+            config,
+            "127.0.0.1:25599",
+            "7",
+            "3",
+            "--server=127.0.0.1:1",
+            "--message",
+            "one",
+            "--message",
+            "two",
+            "--action",
+            "chat:0:100",
+        ]);
+        assert_eq!(settings.server.as_deref(), Some("127.0.0.1:1"));
+        assert_eq!((settings.count, settings.threads), (9, Some(3)));
+        assert_eq!(settings.joins_per_tick, 1.5);
+        assert_eq!(settings.messages, ["one", "two"]);
+        assert_eq!(settings.actions.chat.every, Some(100));
+        assert_eq!(settings.actions.swing.every, Some(20));
+        fs::remove_file(path).unwrap();
+
+        let defaults_only = from_args(&["127.0.0.1", "1"]);
+        assert_eq!(defaults_only.messages, defaults.messages);
+        assert_eq!(defaults_only.actions, defaults.actions);
+    }
+
+    #[test]
+    fn bad_arguments_are_an_error() {
+        assert!(parse(&["127.0.0.1", "1", "--help"]).unwrap().is_none());
+        assert!(parse(&["127.0.0.1", "1", "--threads"]).is_err());
+        assert!(parse(&["127.0.0.1", "1", "--move=yes"]).is_err());
+        assert!(parse(&["127.0.0.1", "1", "--unknown"]).is_err());
+        assert!(parse(&["127.0.0.1", "1", "2", "3"]).is_err());
+        assert!(parse(&["127.0.0.1", "many"]).is_err());
+        assert!(parse(&["--count", "1"]).is_err());
+        assert!(parse(&["127.0.0.1", "1", "--config", "scenario.yaml"]).is_err());
+    }
+
+    #[test]
+    fn flags_turn_off_switches_of_the_scenario() {
+        let path = scenario(
+            "switches",
+            "server = \"127.0.0.1:25565\"\ncount = 5\nreconnect = true\n\
+             detect_protocol = true\nlog_chat = true\n[actions]\nrandom_phase = true\n",
+        );
+        let config = path.to_str().unwrap();
+        let settings = from_args(&["--config", config]);
+        assert!(settings.reconnect && settings.detect_protocol && settings.log_chat);
+        assert!(settings.actions.random_phase);
+
+        let settings = from_args(&[
+            "--config",
+            config,
+            "--no-reconnect",
+            "--no-detect-protocol",
+            "--no-log-chat",
+            "--no-random-phase",
+        ]);
+        assert!(!settings.reconnect && !settings.detect_protocol && !settings.log_chat);
+        assert!(!settings.actions.random_phase);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,77 +1,133 @@
-use std::usize;
+use crate::protocol::{Protocol, Serverbound};
+use crate::{packet_utils::Buf, Bot, Context, ProtocolState};
 
-use crate::{packet_utils::Buf, Bot, Compression, ProtocolState};
-
-pub fn process_cookie_request_packet(buf: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
-    let identifier = buf.read_sized_string();
-    bot.send_packet(write_cookie_response(identifier), compression);
+pub fn process_cookie_request_packet(buf: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let identifier = match buf.read_sized_string() {
+        Ok(identifier) => identifier.to_owned(),
+        Err(err) => return bot.kick(format!("invalid cookie request: {}", err)),
+    };
+    let buf = write_cookie_response(
+        ctx.protocol,
+        &identifier,
+        bot.cookies.get(&identifier).map(Vec::as_slice),
+    );
+    bot.send_packet(buf, ctx);
 }
 
 /// Finish Configuration
-pub fn process_finish_configuration(
-    _buffer: &mut Buf,
-    bot: &mut Bot,
-    compression: &mut Compression,
-) {
-    bot.send_packet(write_acknowledge_configuration(), compression);
+pub fn process_finish_configuration(_buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    bot.send_packet(write_acknowledge_configuration(ctx.protocol), ctx);
 
     bot.state = ProtocolState::Play;
+    ctx.metrics.joined += 1;
+    if let Some(login_started) = bot.login_started {
+        ctx.metrics
+            .join_latency
+            .record_duration(login_started.elapsed());
+    }
 }
 
 /// Clientbound Keep Alive (configuration)
-pub fn process_keep_alive_packet(buffer: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
-    bot.send_packet(write_keep_alive_packet(buffer.read_u64()), compression);
+pub fn process_keep_alive_packet(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    ctx.metrics.keep_alives += 1;
+    ctx.metrics
+        .keep_alive_interval
+        .record_interval(&mut bot.last_keep_alive);
+    bot.send_packet(
+        write_keep_alive_packet(ctx.protocol, buffer.read_u64()),
+        ctx,
+    );
 }
 
 /// Ping (configuration)
-pub fn process_ping(buffer: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
-    bot.send_packet(write_pong(buffer.read_u32()), compression);
+pub fn process_ping(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    ctx.metrics
+        .server_ping_interval
+        .record_interval(&mut bot.last_ping);
+    bot.send_packet(write_pong(ctx.protocol, buffer.read_u32()), ctx);
 }
 
 /// Add Resource Pack (configuration)
-pub fn process_resource_pack(buffer: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
-    bot.send_packet(
-        write_acknowledge_resource_pack(buffer.read_u128()),
-        compression,
-    );
+pub fn process_resource_pack(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let id = if ctx.protocol.resource_pack_ids {
+        buffer.read_u128()
+    } else {
+        0
+    };
+    bot.send_packet(write_acknowledge_resource_pack(ctx.protocol, id), ctx);
 }
 
-pub fn process_transfer(buffer: &mut Buf, bot: &mut Bot, _compression: &mut Compression) {
-    // Goofy lifetimes
-    let address = buffer.read_sized_string().to_owned();
-    let port = buffer.read_u16();
+/// Transfer (configuration and play), the worker reconnects the bot once the packets are processed
+pub fn process_transfer(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    let host = match buffer.read_sized_string() {
+        Ok(host) => host.to_owned(),
+        Err(err) => return bot.kick(format!("invalid transfer: {}", err)),
+    };
+    let port = buffer.read_var_u32().0 as u16;
 
-    println!("Server requested transfer to {address}:{port} but it isnt implemented! Please turn off online mode! Disconnecting bot {}", bot.name);
-    bot.kicked = true;
+    println!("bot \"{}\" transferred to {}:{}", bot.name, host, port);
+    bot.transfer = Some((host, port));
 }
 
-pub fn process_known_packs(_buffer: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
-    bot.send_packet(write_known_packets(), compression);
+/// Largest cookie payload the client accepts
+const MAX_COOKIE_SIZE: usize = 5120;
+
+/// Store Cookie (configuration and play), the cookie is sent back in Cookie Responses, also
+/// after a transfer
+pub fn process_store_cookie(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    let identifier = match buffer.read_sized_string() {
+        Ok(identifier) => identifier.to_owned(),
+        Err(err) => return bot.kick(format!("invalid cookie: {}", err)),
+    };
+    let payload = match buffer.read_sized_bytes() {
+        Ok(payload) => payload.to_vec(),
+        Err(err) => return bot.kick(format!("invalid cookie {}: {}", identifier, err)),
+    };
+    if payload.len() > MAX_COOKIE_SIZE {
+        bot.kick(format!(
+            "cookie {} is {} bytes long",
+            identifier,
+            payload.len()
+        ));
+        return;
+    }
+    bot.cookies.insert(identifier, payload);
 }
 
-pub fn write_cookie_response(identifier: &str) -> Buf {
+pub fn process_known_packs(_buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    bot.send_packet(write_known_packets(ctx.protocol), ctx);
+}
+
+pub fn write_cookie_response(protocol: &Protocol, identifier: &str, payload: Option<&[u8]>) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x01);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Config, Serverbound::CookieResponse));
 
     buf.write_sized_str(identifier);
-    buf.write_bool(false);
+    buf.write_bool(payload.is_some());
+    if let Some(payload) = payload {
+        buf.write_var_u32(payload.len() as u32);
+        buf.write_bytes(payload);
+    }
 
     buf
 }
 
 /// Acknowledge Finish Configuration
-pub fn write_acknowledge_configuration() -> Buf {
+pub fn write_acknowledge_configuration(protocol: &Protocol) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x03);
+    buf.write_packet_id(protocol.serverbound(
+        ProtocolState::Config,
+        Serverbound::AcknowledgeFinishConfiguration,
+    ));
 
     buf
 }
 
 /// Serverbound Keep Alive (configuration)
-pub fn write_keep_alive_packet(id: u64) -> Buf {
+pub fn write_keep_alive_packet(protocol: &Protocol, id: u64) -> Buf {
     // ClientKeepAlivePacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x04);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Config, Serverbound::KeepAlive));
 
     buf.write_u64(id);
 
@@ -79,10 +135,10 @@ pub fn write_keep_alive_packet(id: u64) -> Buf {
 }
 
 /// Pong (configuration)
-pub fn write_pong(id: u32) -> Buf {
+pub fn write_pong(protocol: &Protocol, id: u32) -> Buf {
     // ClientKeepAlivePacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x05);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Config, Serverbound::Pong));
 
     buf.write_u32(id);
 
@@ -90,36 +146,40 @@ pub fn write_pong(id: u32) -> Buf {
 }
 
 /// Resource Pack Response (configuration)
-pub fn write_acknowledge_resource_pack(id: u128) -> Buf {
+pub fn write_acknowledge_resource_pack(protocol: &Protocol, id: u128) -> Buf {
     // ClientKeepAlivePacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x06);
+    buf.write_packet_id(
+        protocol.serverbound(ProtocolState::Config, Serverbound::ResourcePackResponse),
+    );
 
-    buf.write_u128(id);
+    if protocol.resource_pack_ids {
+        buf.write_u128(id);
+    }
     buf.write_var_u32(3); // Accepted
 
     buf
 }
 
-pub fn write_known_packets() -> Buf {
+pub fn write_known_packets(protocol: &Protocol) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x07);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Config, Serverbound::KnownPacks));
 
     buf.write_var_u32(0);
 
     buf
 }
 
-const VIEW_DISTANCE: u8 = 10u8;
-
 /// Client Information (configuration)
-pub fn write_client_settings() -> Buf {
+pub fn write_client_settings(protocol: &Protocol, view_distance: u8) -> Buf {
     // ClientSettingsPacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x00);
+    buf.write_packet_id(
+        protocol.serverbound(ProtocolState::Config, Serverbound::ClientInformation),
+    );
 
     buf.write_sized_str("en_US");
-    buf.write_u8(VIEW_DISTANCE);
+    buf.write_u8(view_distance);
     buf.write_var_u32(0);
     buf.write_bool(true);
     buf.write_u8(0xFF);
@@ -129,3 +189,89 @@ pub fn write_client_settings() -> Buf {
 
     buf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOLS;
+    use crate::settings::Settings;
+
+    fn store_cookie(identifier: &str, payload: &[u8]) -> Buf {
+        let mut buf = Buf::new();
+        buf.write_sized_str(identifier);
+        buf.write_var_u32(payload.len() as u32);
+        buf.write_bytes(payload);
+        buf
+    }
+
+    fn cookie_request(identifier: &str) -> Buf {
+        let mut buf = Buf::new();
+        buf.write_sized_str(identifier);
+        buf
+    }
+
+    #[test]
+    fn stored_cookies_are_sent_back() {
+        // Cookies came with 1.20.5
+        for protocol in PROTOCOLS.iter().filter(|protocol| protocol.version >= 766) {
+            let mut ctx = Context::for_protocol(protocol, Settings::default());
+            let (mut bot, mut peer) = Bot::paired(ProtocolState::Config);
+            let response_id =
+                protocol.serverbound(ProtocolState::Config, Serverbound::CookieResponse);
+
+            process_store_cookie(&mut store_cookie("mc:a", &[1, 2, 3]), &mut bot, &mut ctx);
+            process_store_cookie(&mut store_cookie("mc:a", &[4, 5]), &mut bot, &mut ctx);
+            process_cookie_request_packet(&mut cookie_request("mc:a"), &mut bot, &mut ctx);
+            process_cookie_request_packet(&mut cookie_request("mc:b"), &mut bot, &mut ctx);
+
+            let mut known = Bot::sent(&mut peer);
+            assert_eq!(known.read_var_u32().0, response_id);
+            assert_eq!(known.read_sized_string().unwrap(), "mc:a");
+            assert!(known.read_bool());
+            assert_eq!(known.read_sized_bytes().unwrap(), [4, 5]);
+            let mut unknown = Bot::sent(&mut peer);
+            assert_eq!(unknown.read_var_u32().0, response_id);
+            assert_eq!(unknown.read_sized_string().unwrap(), "mc:b");
+            assert!(!unknown.read_bool());
+            assert_eq!(unknown.remaining(), 0);
+            assert!(!bot.kicked);
+        }
+    }
+
+    #[test]
+    fn oversized_cookies_are_refused() {
+        let mut ctx = Context::for_protocol(&PROTOCOLS[PROTOCOLS.len() - 1], Settings::default());
+        let (mut bot, _peer) = Bot::paired(ProtocolState::Config);
+        let mut cookie = store_cookie("mc:a", &[0; MAX_COOKIE_SIZE + 1]);
+        process_store_cookie(&mut cookie, &mut bot, &mut ctx);
+        assert!(bot.kicked && bot.cookies.is_empty());
+
+        // Longer than the packet
+        let (mut bot, _peer) = Bot::paired(ProtocolState::Config);
+        let mut cookie = cookie_request("mc:a");
+        cookie.write_var_u32(10);
+        cookie.write_bytes(&[1, 2]);
+        process_store_cookie(&mut cookie, &mut bot, &mut ctx);
+        assert!(bot.kicked && bot.cookies.is_empty());
+    }
+
+    #[test]
+    fn transfers_are_followed_once_the_packets_are_processed() {
+        let protocol = &PROTOCOLS[PROTOCOLS.len() - 1];
+        let mut ctx = Context::for_protocol(protocol, Settings::default());
+        let (mut bot, _peer) = Bot::paired(ProtocolState::Config);
+
+        let mut buf = Buf::new();
+        buf.write_sized_str("lobby.example.org");
+        buf.write_var_u32(25570);
+        process_transfer(&mut buf, &mut bot, &mut ctx);
+        assert_eq!(bot.transfer, Some(("lobby.example.org".to_owned(), 25570)));
+        assert!(!bot.kicked);
+
+        let mut buf = Buf::new();
+        buf.write_var_u32(100);
+        buf.write_bytes(b"short");
+        process_transfer(&mut buf, &mut bot, &mut ctx);
+        assert!(bot.kicked);
+    }
+}
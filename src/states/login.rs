@@ -1,5 +1,142 @@
-use crate::{packet_utils::Buf, states::config};
-use crate::{Bot, Compression, ProtocolState};
+use crate::encryption::{self, Encryption};
+use crate::protocol::{Protocol, Serverbound};
+use crate::settings::Settings;
+use crate::{packet_utils::Buf, session, states::config};
+use crate::{Bot, Context, Error, ProtocolState};
+use mio::Token;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Client side of the session handshake, run before the Encryption Response is sent.
+///
+/// Joins may block, they run on the helper threads of `Joins`.
+pub trait Authenticator: Send + Sync {
+    fn join(&self, uuid: u128, server_hash: &str) -> Result<(), Error>;
+}
+
+/// Skips the handshake, enough for servers in offline mode
+pub struct Offline;
+
+impl Authenticator for Offline {
+    fn join(&self, _uuid: u128, _server_hash: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Joins through a Yggdrasil style session server with a generated access token
+pub struct SessionServer {
+    pub url: String,
+}
+
+impl Authenticator for SessionServer {
+    fn join(&self, uuid: u128, server_hash: &str) -> Result<(), Error> {
+        let access_token = format!("{:032x}", rand::random::<u128>());
+        session::join(&self.url, &access_token, uuid, server_hash)
+    }
+}
+
+pub fn authenticator(settings: &Settings) -> Arc<dyn Authenticator> {
+    match &settings.session_server {
+        Some(url) => Arc::new(SessionServer { url: url.clone() }),
+        None => Arc::new(Offline),
+    }
+}
+
+/// Helper threads of every worker thread running session joins
+const JOIN_THREADS: usize = 4;
+
+struct JoinRequest {
+    token: Token,
+    id: u64,
+    uuid: u128,
+    server_hash: String,
+}
+
+/// A finished session join of the bot with `token`, `id` tells it apart from earlier joins of
+/// a bot that reconnected in the meantime
+pub struct JoinResult {
+    pub token: Token,
+    pub id: u64,
+    pub result: Result<(), String>,
+}
+
+/// Runs the session joins of a worker thread's bots on helper threads, so a slow session
+/// server doesn't hold up the ticks of every bot on the thread. The worker collects the
+/// results with `finished` every tick.
+pub struct Joins {
+    authenticator: Arc<dyn Authenticator>,
+    /// Started with the first join, servers in offline mode never ask for one
+    requests: Option<Sender<JoinRequest>>,
+    results_sender: Sender<JoinResult>,
+    results: Receiver<JoinResult>,
+    next_id: u64,
+}
+
+impl Joins {
+    pub fn new(authenticator: Arc<dyn Authenticator>) -> Joins {
+        let (results_sender, results) = mpsc::channel();
+        Joins {
+            authenticator,
+            requests: None,
+            results_sender,
+            results,
+            next_id: 0,
+        }
+    }
+
+    /// Queues the join of the bot with `token`, returns the id its result will carry
+    pub fn start(&mut self, token: Token, uuid: u128, server_hash: String) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = JoinRequest {
+            token,
+            id,
+            uuid,
+            server_hash,
+        };
+        let (authenticator, results_sender) = (&self.authenticator, &self.results_sender);
+        let requests = self.requests.get_or_insert_with(|| {
+            let (requests, queue) = mpsc::channel::<JoinRequest>();
+            let queue = Arc::new(Mutex::new(queue));
+            for _ in 0..JOIN_THREADS {
+                let queue = queue.clone();
+                let results = results_sender.clone();
+                let authenticator = authenticator.clone();
+                std::thread::spawn(move || loop {
+                    // The worker dropped the sender, it is done
+                    let request = match queue.lock().unwrap().recv() {
+                        Ok(request) => request,
+                        Err(_) => return,
+                    };
+                    let result = authenticator
+                        .join(request.uuid, &request.server_hash)
+                        .map_err(|err| err.to_string());
+                    let _ = results.send(JoinResult {
+                        token: request.token,
+                        id: request.id,
+                        result,
+                    });
+                });
+            }
+            requests
+        });
+        // The helpers only stop once the sender is dropped
+        let _ = requests.send(request);
+        id
+    }
+
+    pub fn finished(&self) -> Vec<JoinResult> {
+        self.results.try_iter().collect()
+    }
+}
+
+/// Encryption Response of a bot waiting for its session join
+pub struct PendingJoin {
+    id: u64,
+    shared_secret: [u8; 16],
+    encrypted_secret: Vec<u8>,
+    encrypted_token: Vec<u8>,
+}
 
 //c2s
 
@@ -22,9 +159,9 @@ pub fn write_handshake_packet(
 }
 
 /// Login Start
-pub fn write_login_start_packet(username: &str, uuid: u128) -> Buf {
+pub fn write_login_start_packet(protocol: &Protocol, username: &str, uuid: u128) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x00);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Login, Serverbound::LoginStart));
 
     buf.write_sized_str(username);
     buf.write_u128(uuid);
@@ -32,9 +169,30 @@ pub fn write_login_start_packet(username: &str, uuid: u128) -> Buf {
     buf
 }
 
-pub fn write_plugin_message_response(message: u32) -> Buf {
+/// Encryption Response
+pub fn write_encryption_response(
+    protocol: &Protocol,
+    shared_secret: &[u8],
+    verify_token: &[u8],
+) -> Buf {
+    let mut buf = Buf::new();
+    buf.write_packet_id(
+        protocol.serverbound(ProtocolState::Login, Serverbound::EncryptionResponse),
+    );
+
+    buf.write_var_u32(shared_secret.len() as u32);
+    buf.write_bytes(shared_secret);
+    buf.write_var_u32(verify_token.len() as u32);
+    buf.write_bytes(verify_token);
+
+    buf
+}
+
+pub fn write_plugin_message_response(protocol: &Protocol, message: u32) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x02);
+    buf.write_packet_id(
+        protocol.serverbound(ProtocolState::Login, Serverbound::LoginPluginResponse),
+    );
 
     buf.write_var_u32(message);
     buf.write_bool(false);
@@ -43,66 +201,193 @@ pub fn write_plugin_message_response(message: u32) -> Buf {
 }
 
 /// Login Acknowledged
-pub fn write_login_acknowledged() -> Buf {
+pub fn write_login_acknowledged(protocol: &Protocol) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x03);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Login, Serverbound::LoginAcknowledged));
 
     buf
 }
 
-pub fn write_cookie_response(identifier: &str) -> Buf {
+pub fn write_cookie_response(protocol: &Protocol, identifier: &str, payload: Option<&[u8]>) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x04);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Login, Serverbound::CookieResponse));
 
     buf.write_sized_str(identifier);
-    buf.write_bool(false);
+    buf.write_bool(payload.is_some());
+    if let Some(payload) = payload {
+        buf.write_var_u32(payload.len() as u32);
+        buf.write_bytes(payload);
+    }
 
     buf
 }
 
 //s2c
 
-pub fn process_encryption_request_packet(
-    _buffer: &mut Buf,
-    bot: &mut Bot,
-    _compression: &mut Compression,
-) {
-    println!("Server requested encryption but it isnt implemented! Please turn off online mode!");
-    bot.kicked = true;
+/// Encryption Request
+pub fn process_encryption_request_packet(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let server_id = match buffer.read_sized_string() {
+        Ok(server_id) => server_id.to_owned(),
+        Err(err) => return bot.kick(format!("invalid encryption request: {}", err)),
+    };
+    let public_key = match buffer.read_sized_bytes() {
+        Ok(public_key) => public_key.to_vec(),
+        Err(err) => return bot.kick(format!("invalid encryption request: {}", err)),
+    };
+    let verify_token = match buffer.read_sized_bytes() {
+        Ok(verify_token) => verify_token.to_vec(),
+        Err(err) => return bot.kick(format!("invalid encryption request: {}", err)),
+    };
+    // Older versions always expect the client to authenticate
+    let should_authenticate = !ctx.protocol.optional_authentication || buffer.read_bool();
+
+    let shared_secret: [u8; 16] = rand::random();
+    let encrypted =
+        encryption::encrypt_with_public_key(&public_key, &shared_secret).and_then(|secret| {
+            Ok((
+                secret,
+                encryption::encrypt_with_public_key(&public_key, &verify_token)?,
+            ))
+        });
+    let (encrypted_secret, encrypted_token) = match encrypted {
+        Ok(encrypted) => encrypted,
+        Err(err) => {
+            println!("could not encrypt the shared secret: {}", err);
+            bot.kick(format!("invalid encryption request: {}", err));
+            return;
+        }
+    };
+
+    let pending = PendingJoin {
+        id: 0,
+        shared_secret,
+        encrypted_secret,
+        encrypted_token,
+    };
+    if should_authenticate {
+        // The response waits until the session server answered, see `finish_join`
+        let server_hash = encryption::server_hash(&server_id, &shared_secret, &public_key);
+        let id = ctx.joins.start(bot.token, bot.uuid, server_hash);
+        bot.pending_join = Some(PendingJoin { id, ..pending });
+    } else {
+        send_encryption_response(bot, ctx, pending);
+    }
+}
+
+/// The session join of a bot waiting in `pending_join` finished
+pub fn finish_join(bot: &mut Bot, ctx: &mut Context, join: JoinResult) {
+    let pending = match bot.pending_join.take() {
+        Some(pending) if pending.id == join.id => pending,
+        // Left over from before the bot reconnected
+        pending => {
+            bot.pending_join = pending;
+            return;
+        }
+    };
+    match join.result {
+        Ok(()) => send_encryption_response(bot, ctx, pending),
+        Err(err) => {
+            println!("bot \"{}\" could not join the session: {}", bot.name, err);
+            bot.kick(format!("session join failed: {}", err));
+        }
+    }
+}
+
+fn send_encryption_response(bot: &mut Bot, ctx: &mut Context, pending: PendingJoin) {
+    // The response is the last packet sent in plain text
+    bot.send_packet(
+        write_encryption_response(
+            ctx.protocol,
+            &pending.encrypted_secret,
+            &pending.encrypted_token,
+        ),
+        ctx,
+    );
+    bot.encryption = Some(Encryption::new(&pending.shared_secret));
 }
 
 /// Login Success
-pub fn process_login_success_packet(
-    buffer: &mut Buf,
-    bot: &mut Bot,
-    compression: &mut Compression,
-) {
+pub fn process_login_success_packet(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
     let _uuid = buffer.read_u128();
     let _name = buffer.read_sized_string();
     let _properties = buffer.read_var_u32();
-    let _strict_error_handeling = buffer.read_bool();
+    if ctx.protocol.strict_error_handling {
+        let _strict_error_handling = buffer.read_bool();
+    }
 
-    bot.state = ProtocolState::Config;
+    bot.send_packet(write_login_acknowledged(ctx.protocol), ctx);
 
-    bot.send_packet(write_login_acknowledged(), compression);
-    bot.send_packet(config::write_client_settings(), compression);
+    bot.state = ProtocolState::Config;
+    bot.send_packet(
+        config::write_client_settings(ctx.protocol, ctx.settings.view_distance),
+        ctx,
+    );
 }
 
 /// Set Compression
-pub fn process_set_compression_packet(
-    buf: &mut Buf,
-    bot: &mut Bot,
-    _compression: &mut Compression,
-) {
+pub fn process_set_compression_packet(buf: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
     bot.compression_threshold = buf.read_var_u32().0 as i32;
 }
 
-pub fn process_plugin_message_request(buf: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
+pub fn process_plugin_message_request(buf: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
     let identifier = buf.read_var_u32().0;
-    bot.send_packet(write_plugin_message_response(identifier), compression);
+    bot.send_packet(write_plugin_message_response(ctx.protocol, identifier), ctx);
 }
 
-pub fn process_cookie_request_packet(buf: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
-    let identifier = buf.read_sized_string();
-    bot.send_packet(write_cookie_response(identifier), compression);
+pub fn process_cookie_request_packet(buf: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let identifier = match buf.read_sized_string() {
+        Ok(identifier) => identifier.to_owned(),
+        Err(err) => return bot.kick(format!("invalid cookie request: {}", err)),
+    };
+    let buf = write_cookie_response(
+        ctx.protocol,
+        &identifier,
+        bot.cookies.get(&identifier).map(Vec::as_slice),
+    );
+    bot.send_packet(buf, ctx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOLS;
+    use crate::settings::Settings;
+
+    fn encryption_request(public_key: &[u8], verify_token_length: u32) -> Buf {
+        let mut buf = Buf::new();
+        buf.write_sized_str("");
+        buf.write_var_u32(public_key.len() as u32);
+        buf.write_bytes(public_key);
+        buf.write_var_u32(verify_token_length);
+        buf.write_bytes(&[0; 4]);
+        buf.write_bool(false);
+        buf
+    }
+
+    #[test]
+    fn malformed_encryption_requests_kick() {
+        for protocol in PROTOCOLS {
+            let mut ctx = Context::for_protocol(protocol, Settings::default());
+            for (public_key, verify_token_length) in [
+                // Not a DER encoded key
+                (&[0x30, 0x03, 1, 2, 3][..], 4),
+                // A verify token longer than the packet
+                (&[0x30, 0x03, 1, 2, 3][..], 400),
+            ] {
+                let (mut bot, _peer) = Bot::paired(ProtocolState::Login);
+                let mut buf = encryption_request(public_key, verify_token_length);
+                process_encryption_request_packet(&mut buf, &mut bot, &mut ctx);
+                assert!(bot.kicked);
+                assert!(bot.encryption.is_none() && bot.pending_join.is_none());
+            }
+
+            // A public key longer than the packet
+            let (mut bot, _peer) = Bot::paired(ProtocolState::Login);
+            let mut buf = Buf::new();
+            buf.write_sized_str("");
+            buf.write_var_u32(1 << 20);
+            process_encryption_request_packet(&mut buf, &mut bot, &mut ctx);
+            assert!(bot.kicked);
+        }
+    }
 }
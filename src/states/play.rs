@@ -1,75 +1,404 @@
+use crate::chat::{self, Chat, ChatKind};
+use crate::entity::Entity;
 use crate::packet_utils::Buf;
-use crate::{Bot, Compression};
+use crate::physics::Movement;
+use crate::protocol::{Protocol, Serverbound};
+use crate::{behaviour, nbt, text, Bot, Context, Error, ProtocolState};
 
 /// Cookie Request (play)
-pub fn process_cookie_request_packet(
-    buffer: &mut Buf,
-    bot: &mut Bot,
-    compression: &mut Compression,
-) {
-    let identifier = buffer.read_sized_string();
-    bot.send_packet(write_cookie_response(identifier), compression);
+pub fn process_cookie_request_packet(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let identifier = match buffer.read_sized_string() {
+        Ok(identifier) => identifier.to_owned(),
+        Err(err) => return bot.kick(format!("invalid cookie request: {}", err)),
+    };
+    let buf = write_cookie_response(
+        ctx.protocol,
+        &identifier,
+        bot.cookies.get(&identifier).map(Vec::as_slice),
+    );
+    bot.send_packet(buf, ctx);
 }
 
 /// Clientbound Keep Alive (play)
-pub fn process_keep_alive_packet(buffer: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
-    bot.send_packet(write_keep_alive_packet(buffer.read_u64()), compression);
+pub fn process_keep_alive_packet(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    ctx.metrics.keep_alives += 1;
+    ctx.metrics
+        .keep_alive_interval
+        .record_interval(&mut bot.last_keep_alive);
+    bot.send_packet(
+        write_keep_alive_packet(ctx.protocol, buffer.read_u64()),
+        ctx,
+    );
+}
+
+/// Ping (play)
+pub fn process_ping(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    ctx.metrics
+        .server_ping_interval
+        .record_interval(&mut bot.last_ping);
+    bot.send_packet(write_pong(ctx.protocol, buffer.read_u32()), ctx);
+}
+
+/// Ping Response (play), answers the probe sent by the bot
+pub fn process_ping_response(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let payload = buffer.read_u64();
+    if let Some((sent_payload, sent_at)) = bot.probe {
+        if sent_payload == payload {
+            ctx.metrics.probe_rtt.record_duration(sent_at.elapsed());
+            bot.probe = None;
+        }
+    }
 }
 
 /// Disconnect (login/config/play)
-pub fn process_kick(buffer: &mut Buf, bot: &mut Bot, _compression: &mut Compression) {
-    println!("bot was kicked for \"{}\"", buffer.read_sized_string());
-    bot.kicked = true;
+pub fn process_kick(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    // The reason is a JSON string during login, and NBT in config and play since 1.20.3
+    let reason = match bot.state {
+        ProtocolState::Config | ProtocolState::Play => read_text(buffer, ctx.protocol),
+        _ => buffer.read_sized_string().map(text::json_to_plain),
+    }
+    .unwrap_or_else(|err| format!("unreadable reason: {}", err));
+    println!("bot was kicked for \"{}\"", reason);
+    ctx.metrics.record_kick(&reason);
+    bot.kick(reason);
 }
 
 /// Login (play)
-pub fn process_join_game(buffer: &mut Buf, bot: &mut Bot, _compression: &mut Compression) {
+pub fn process_join_game(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
     bot.entity_id = buffer.read_u32();
+    behaviour::run(bot, |behaviour, bot| behaviour.on_join(bot, ctx));
 }
 
 /// Synchronize Player Position
-pub fn process_teleport(buffer: &mut Buf, bot: &mut Bot, compression: &mut Compression) {
+pub fn process_teleport(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
     let x = buffer.read_f64();
     let y = buffer.read_f64();
     let z = buffer.read_f64();
-    let _yaw = buffer.read_f32();
-    let _pitch = buffer.read_f32();
+    let yaw = buffer.read_f32();
+    let pitch = buffer.read_f32();
+    // Every set bit makes the field relative to the current value
     let flags = buffer.read_byte();
-    if flags & 0b10000 == 0 {
-        bot.x = x;
-    } else {
-        bot.x += x;
+    let relative = |bit: u8, current: f64, value: f64| {
+        if flags & bit == 0 {
+            value
+        } else {
+            current + value
+        }
+    };
+    bot.x = relative(0x01, bot.x, x);
+    bot.y = relative(0x02, bot.y, y);
+    bot.z = relative(0x04, bot.z, z);
+    bot.body.yaw = relative(0x08, bot.body.yaw as f64, yaw as f64) as f32;
+    bot.body.pitch = relative(0x10, bot.body.pitch as f64, pitch as f64) as f32;
+    bot.body.teleported();
+    bot.send_packet(
+        write_tele_confirm(ctx.protocol, buffer.read_var_u32().0),
+        ctx,
+    );
+    bot.teleported = true;
+}
+
+/// Respawn, sent on death and on every dimension change. The server sends the chunks and
+/// entities around the new player again, so the old ones are dropped.
+pub fn process_respawn(_buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    bot.entities.clear();
+    ctx.world.unload_all(&mut bot.chunks);
+}
+
+/// Chunk Data and Update Light
+pub fn process_chunk_data(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    ctx.world.load_chunk(buffer, &mut bot.chunks);
+}
+
+/// Chunk Batch Finished, the server sends no more chunks until the batch is acknowledged
+pub fn process_chunk_batch(_buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    bot.send_packet(
+        write_chunk_batch_received(ctx.protocol, CHUNKS_PER_TICK),
+        ctx,
+    );
+}
+
+/// Unload Chunk
+pub fn process_unload_chunk(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let z = buffer.read_u32() as i32;
+    let x = buffer.read_u32() as i32;
+    ctx.world.unload_chunk((x, z), &mut bot.chunks);
+}
+
+/// Block Update
+pub fn process_block_update(buffer: &mut Buf, _bot: &mut Bot, ctx: &mut Context) {
+    let (x, y, z) = buffer.read_block_position();
+    let state = buffer.read_var_u32().0;
+    ctx.world.set_block(x, y, z, state);
+}
+
+/// Update Section Blocks
+pub fn process_section_blocks_update(buffer: &mut Buf, _bot: &mut Bot, ctx: &mut Context) {
+    let (section_x, section_y, section_z) = buffer.read_section_position();
+    let count = buffer.read_var_u32().0;
+    for _ in 0..count {
+        // The state id followed by the x, z and y of the block in the section
+        let block = buffer.read_var_u64().0;
+        let state = (block >> 12) as u32;
+        ctx.world.set_block(
+            section_x * 16 + (block >> 8 & 15) as i32,
+            section_y * 16 + (block & 15) as i32,
+            section_z * 16 + (block >> 4 & 15) as i32,
+            state,
+        );
+    }
+}
+
+/// Spawn Entity
+pub fn process_spawn_entity(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    let id = buffer.read_var_u32().0;
+    let uuid = buffer.read_u128();
+    let kind = buffer.read_var_u32().0;
+    let x = buffer.read_f64();
+    let y = buffer.read_f64();
+    let z = buffer.read_f64();
+    let pitch = read_angle(buffer);
+    let yaw = read_angle(buffer);
+    bot.entities.spawn(
+        id,
+        Entity {
+            uuid,
+            kind,
+            x,
+            y,
+            z,
+            yaw,
+            pitch,
+            on_ground: false,
+            flags: 0,
+        },
+    );
+}
+
+/// Remove Entities
+pub fn process_remove_entities(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    match buffer.read_var_u32_slice() {
+        Ok(ids) => ids.into_iter().for_each(|id| bot.entities.remove(id)),
+        Err(err) => bot.kick(format!("invalid remove entities: {}", err)),
     }
-    if flags & 0b01000 == 0 {
-        bot.y = y;
+}
+
+/// Update Entity Position
+pub fn process_entity_position(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    move_entity(buffer, bot, true, false);
+}
+
+/// Update Entity Position and Rotation
+pub fn process_entity_position_rotation(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    move_entity(buffer, bot, true, true);
+}
+
+/// Update Entity Rotation
+pub fn process_entity_rotation(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    move_entity(buffer, bot, false, true);
+}
+
+fn move_entity(buffer: &mut Buf, bot: &mut Bot, position: bool, rotation: bool) {
+    let id = buffer.read_var_u32().0;
+    let entity = match bot.entities.get_mut(id) {
+        Some(entity) => entity,
+        None => return,
+    };
+    if position {
+        // Deltas are in 1/4096 of a block
+        entity.x += buffer.read_u16() as i16 as f64 / 4096.0;
+        entity.y += buffer.read_u16() as i16 as f64 / 4096.0;
+        entity.z += buffer.read_u16() as i16 as f64 / 4096.0;
+    }
+    if rotation {
+        entity.yaw = read_angle(buffer);
+        entity.pitch = read_angle(buffer);
+    }
+    entity.on_ground = buffer.read_bool();
+}
+
+/// Teleport Entity
+pub fn process_teleport_entity(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    let id = buffer.read_var_u32().0;
+    if let Some(entity) = bot.entities.get_mut(id) {
+        entity.x = buffer.read_f64();
+        entity.y = buffer.read_f64();
+        entity.z = buffer.read_f64();
+        entity.yaw = read_angle(buffer);
+        entity.pitch = read_angle(buffer);
+        entity.on_ground = buffer.read_bool();
+    }
+}
+
+/// Set Entity Metadata
+pub fn process_entity_metadata(buffer: &mut Buf, bot: &mut Bot, _ctx: &mut Context) {
+    let id = buffer.read_var_u32().0;
+    let index = buffer.read_byte();
+    // Only the flags byte is kept, it is always entry 0. Reading further would need the layout
+    // of every metadata type, so the other entries are skipped with the rest of the packet.
+    if index == 0 && buffer.read_var_u32().0 == 0 {
+        let flags = buffer.read_byte();
+        if let Some(entity) = bot.entities.get_mut(id) {
+            entity.flags = flags;
+        }
+    }
+}
+
+/// Angles are sent in 1/256 of a full turn
+fn read_angle(buffer: &mut Buf) -> f32 {
+    buffer.read_byte() as f32 * 360.0 / 256.0
+}
+
+/// System Chat
+pub fn process_system_chat(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let text = match read_text(buffer, ctx.protocol) {
+        Ok(text) => text,
+        Err(err) => return unreadable_chat(bot, ctx, err),
+    };
+    // Overlay messages are shown above the hotbar rather than in the chat
+    if buffer.read_bool() {
+        return;
+    }
+    received_chat(
+        bot,
+        ctx,
+        Chat {
+            kind: ChatKind::System,
+            sender: None,
+            text,
+        },
+    );
+}
+
+/// Player Chat
+pub fn process_player_chat(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let _sender = buffer.read_u128();
+    let _index = buffer.read_var_u32();
+    if buffer.read_bool() {
+        buffer.read_bytes(256);
+    }
+    let message = match buffer.read_sized_string() {
+        Ok(message) => message.to_owned(),
+        Err(err) => return unreadable_chat(bot, ctx, err),
+    };
+    let _timestamp = buffer.read_u64();
+    let _salt = buffer.read_u64();
+    for _ in 0..buffer.read_var_u32().0 {
+        // Previous messages are referenced by id plus one, or sent in full after a 0
+        if buffer.read_var_u32().0 == 0 {
+            buffer.read_bytes(256);
+        }
+    }
+    // Servers that rewrite messages send what to show next to the signed original
+    let text = if buffer.read_bool() {
+        match read_text(buffer, ctx.protocol) {
+            Ok(text) => text,
+            Err(err) => return unreadable_chat(bot, ctx, err),
+        }
     } else {
-        bot.y += y;
+        message
+    };
+    // Partially filtered messages are followed by a bit set of the filtered characters
+    if buffer.read_var_u32().0 == 2 {
+        for _ in 0..buffer.read_var_u32().0 {
+            buffer.read_u64();
+        }
+    }
+    let sender = match read_chat_sender(buffer, ctx.protocol) {
+        Ok(sender) => sender,
+        Err(err) => return unreadable_chat(bot, ctx, err),
+    };
+    received_chat(
+        bot,
+        ctx,
+        Chat {
+            kind: ChatKind::Player,
+            sender,
+            text,
+        },
+    );
+}
+
+/// Disguised Chat
+pub fn process_disguised_chat(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let read = read_text(buffer, ctx.protocol)
+        .and_then(|text| Ok((text, read_chat_sender(buffer, ctx.protocol)?)));
+    let (text, sender) = match read {
+        Ok(read) => read,
+        Err(err) => return unreadable_chat(bot, ctx, err),
+    };
+    received_chat(
+        bot,
+        ctx,
+        Chat {
+            kind: ChatKind::Disguised,
+            sender,
+            text,
+        },
+    );
+}
+
+/// Reads the chat type and the sender name that follows it, `None` if the chat type is sent
+/// inline, whose layout the bot doesn't know
+fn read_chat_sender(buffer: &mut Buf, protocol: &Protocol) -> Result<Option<String>, Error> {
+    let chat_type = buffer.read_var_u32().0;
+    if protocol.chat_type_holders && chat_type == 0 {
+        return Ok(None);
+    }
+    read_text(buffer, protocol).map(Some)
+}
+
+/// A chat packet whose text couldn't be read is counted and otherwise skipped
+fn unreadable_chat(bot: &mut Bot, ctx: &mut Context, err: Error) {
+    ctx.metrics.chat_unreadable += 1;
+    if ctx.settings.log_chat {
+        println!("{} received unreadable chat: {}", bot.name, err);
+    }
+}
+
+fn received_chat(bot: &mut Bot, ctx: &mut Context, chat: Chat) {
+    bot.chat_received += 1;
+    ctx.metrics.chat_received += 1;
+    if let Some(latency) = chat::probe_latency(&chat.text, &bot.name) {
+        ctx.metrics.chat_latency.record_duration(latency);
     }
-    if flags & 0b00100 == 0 {
-        bot.z = z;
+    if ctx.settings.log_chat {
+        println!("{} received {} chat: {}", bot.name, chat.kind.name(), chat);
+    }
+    behaviour::run(bot, |behaviour, bot| behaviour.on_chat(bot, ctx, &chat));
+}
+
+/// Reads a text component as plain text, it is NBT since 1.20.3 and JSON before
+fn read_text(buffer: &mut Buf, protocol: &Protocol) -> Result<String, Error> {
+    if protocol.nbt_text {
+        Ok(nbt::read_network_nbt(buffer)?
+            .map(|component| text::nbt_to_plain(&component))
+            .unwrap_or_default())
     } else {
-        bot.z += z;
+        Ok(text::json_to_plain(buffer.read_sized_string()?))
     }
-    bot.send_packet(write_tele_confirm(buffer.read_var_u32().0), compression);
-    bot.teleported = true;
 }
 
 /// Cookie Response (play)
-pub fn write_cookie_response(identifier: &str) -> Buf {
+pub fn write_cookie_response(protocol: &Protocol, identifier: &str, payload: Option<&[u8]>) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x11);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::CookieResponse));
 
     buf.write_sized_str(identifier);
-    buf.write_bool(false);
+    buf.write_bool(payload.is_some());
+    if let Some(payload) = payload {
+        buf.write_var_u32(payload.len() as u32);
+        buf.write_bytes(payload);
+    }
 
     buf
 }
 
 /// Chat Message
-pub fn write_chat_message(message: &str) -> Buf {
+pub fn write_chat_message(protocol: &Protocol, message: &str) -> Buf {
     // ClientChatMessagePacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x06);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::ChatMessage));
 
     buf.write_sized_str(message);
 
@@ -83,11 +412,43 @@ pub fn write_chat_message(message: &str) -> Buf {
     buf
 }
 
+/// Chat Command, `command` without the leading slash
+pub fn write_chat_command(protocol: &Protocol, command: &str) -> Buf {
+    let mut buf = Buf::new();
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::ChatCommand));
+
+    buf.write_sized_str(command);
+
+    if !protocol.unsigned_commands {
+        // 1.19 signing fields
+        buf.write_u64(0); // timestamp
+        buf.write_u64(0); // salt
+        buf.write_var_u32(0); // argument signatures
+        buf.write_var_u32(0); // count
+        buf.write_bytes(&[0; 3]); // bitset
+    }
+
+    buf
+}
+
+/// Chunks per tick the bots ask for, the most a vanilla server sends
+const CHUNKS_PER_TICK: f32 = 64.0;
+
+/// Chunk Batch Received
+pub fn write_chunk_batch_received(protocol: &Protocol, chunks_per_tick: f32) -> Buf {
+    let mut buf = Buf::new();
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::ChunkBatchReceived));
+
+    buf.write_f32(chunks_per_tick);
+
+    buf
+}
+
 /// Swing Arm
-pub fn write_animation(off_hand: bool) -> Buf {
+pub fn write_animation(protocol: &Protocol, off_hand: bool) -> Buf {
     // ClientAnimationPacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x36);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::SwingArm));
 
     buf.write_var_u32(if off_hand { 1 } else { 0 });
 
@@ -95,10 +456,15 @@ pub fn write_animation(off_hand: bool) -> Buf {
 }
 
 /// Player Command
-pub fn write_entity_action(entity_id: u32, action_id: u32, jump_boost: u32) -> Buf {
+pub fn write_entity_action(
+    protocol: &Protocol,
+    entity_id: u32,
+    action_id: u32,
+    jump_boost: u32,
+) -> Buf {
     // ClientEntityActionPacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x25);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::PlayerCommand));
 
     buf.write_var_u32(entity_id);
     buf.write_var_u32(action_id);
@@ -108,10 +474,10 @@ pub fn write_entity_action(entity_id: u32, action_id: u32, jump_boost: u32) -> B
 }
 
 /// Set Held Item (serverbound)
-pub fn write_held_slot(slot: u16) -> Buf {
+pub fn write_held_slot(protocol: &Protocol, slot: u16) -> Buf {
     // ClientHeldItemChangePacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x2F);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::SetHeldItem));
 
     buf.write_u16(slot);
 
@@ -119,45 +485,163 @@ pub fn write_held_slot(slot: u16) -> Buf {
 }
 
 /// Confirm Teleportation
-pub fn write_tele_confirm(id: u32) -> Buf {
+pub fn write_tele_confirm(protocol: &Protocol, id: u32) -> Buf {
     // ClientTeleportConfirmPacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x00);
+    buf.write_packet_id(
+        protocol.serverbound(ProtocolState::Play, Serverbound::ConfirmTeleportation),
+    );
 
     buf.write_var_u32(id);
 
     buf
 }
 
-/// Serverbound Keep Alive (play)
-pub fn write_keep_alive_packet(id: u64) -> Buf {
-    // ClientKeepAlivePacket
+/// Pong (play)
+pub fn write_pong(protocol: &Protocol, id: u32) -> Buf {
     let mut buf = Buf::new();
-    buf.write_packet_id(0x18);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::Pong));
 
-    buf.write_u64(id);
+    buf.write_u32(id);
 
     buf
 }
 
-pub fn write_current_pos(bot: &Bot) -> Buf {
-    write_pos(bot.x, bot.y, bot.z, 0.0, 0.0)
+/// Ping Request (play)
+pub fn write_ping_request(protocol: &Protocol, payload: u64) -> Buf {
+    let mut buf = Buf::new();
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::PingRequest));
+
+    buf.write_u64(payload);
+
+    buf
 }
 
-/// Set Player Position and Rotation
-pub fn write_pos(x: f64, y: f64, z: f64, yaw: f32, pitch: f32) -> Buf {
-    // ClientPlayerPositionAndRotationPacket
+/// Serverbound Keep Alive (play)
+pub fn write_keep_alive_packet(protocol: &Protocol, id: u64) -> Buf {
+    // ClientKeepAlivePacket
     let mut buf = Buf::new();
-    buf.write_packet_id(0x1B);
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, Serverbound::KeepAlive));
 
-    buf.write_f64(x);
-    buf.write_f64(y);
-    buf.write_f64(z);
+    buf.write_u64(id);
 
-    buf.write_f32(yaw);
-    buf.write_f32(pitch);
+    buf
+}
 
-    buf.write_bool(false);
+/// Set Player Position, Set Player Position and Rotation, Set Player Rotation or Set Player On
+/// Ground, with the bot's current position and rotation
+pub fn write_movement(protocol: &Protocol, bot: &Bot, movement: Movement) -> Buf {
+    let packet = match movement {
+        Movement::PositionAndRotation => Serverbound::SetPlayerPositionAndRotation,
+        Movement::Position => Serverbound::SetPlayerPosition,
+        Movement::Rotation => Serverbound::SetPlayerRotation,
+        Movement::OnGround => Serverbound::SetPlayerOnGround,
+    };
+    let mut buf = Buf::new();
+    buf.write_packet_id(protocol.serverbound(ProtocolState::Play, packet));
+
+    if let Movement::PositionAndRotation | Movement::Position = movement {
+        buf.write_f64(bot.x);
+        buf.write_f64(bot.y);
+        buf.write_f64(bot.z);
+    }
+    if let Movement::PositionAndRotation | Movement::Rotation = movement {
+        buf.write_f32(bot.body.yaw);
+        buf.write_f32(bot.body.pitch);
+    }
+
+    buf.write_bool(bot.body.on_ground);
 
     buf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_processors;
+    use crate::protocol::{Clientbound, PROTOCOLS};
+    use crate::settings::Settings;
+
+    #[test]
+    fn keep_alives_are_echoed_and_timed() {
+        for protocol in PROTOCOLS {
+            let mut ctx = Context::for_protocol(protocol, Settings::default());
+            let (mut bot, mut peer) = Bot::paired(ProtocolState::Play);
+            for id in [7, u64::MAX] {
+                let mut buf = Buf::new();
+                buf.write_u64(id);
+                process_keep_alive_packet(&mut buf, &mut bot, &mut ctx);
+                let mut sent = Bot::sent(&mut peer);
+                assert_eq!(
+                    sent.read_var_u32().0,
+                    protocol.serverbound(ProtocolState::Play, Serverbound::KeepAlive)
+                );
+                assert_eq!(sent.read_u64(), id);
+            }
+            assert_eq!(ctx.metrics.keep_alives, 2);
+            // The first one starts the interval
+            assert_eq!(ctx.metrics.keep_alive_interval.count(), 1);
+        }
+    }
+
+    #[test]
+    fn only_the_answer_to_the_probe_is_timed() {
+        let mut ctx = Context::for_protocol(&PROTOCOLS[0], Settings::default());
+        let (mut bot, _peer) = Bot::paired(ProtocolState::Play);
+        bot.probe = Some((5, std::time::Instant::now()));
+        for payload in [6, 5, 5] {
+            let mut buf = Buf::new();
+            buf.write_u64(payload);
+            process_ping_response(&mut buf, &mut bot, &mut ctx);
+        }
+        assert_eq!(bot.probe, None);
+        assert_eq!(ctx.metrics.probe_rtt.count(), 1);
+    }
+
+    /// Since 1.20.2 the server stops sending chunks until a batch is acknowledged, so a bot
+    /// that misses Chunk Batch Finished only ever gets its first batch
+    #[test]
+    fn chunk_batches_are_acknowledged() {
+        for protocol in PROTOCOLS {
+            let packet = protocol.clientbound(ProtocolState::Play, 0x0C);
+            assert_eq!(packet, Some(Clientbound::ChunkBatchFinished));
+            assert!(packet_processors::lookup_packet(
+                ProtocolState::Play,
+                Clientbound::ChunkBatchFinished
+            )
+            .is_some());
+
+            let mut buf = write_chunk_batch_received(protocol, CHUNKS_PER_TICK);
+            let expected = if protocol.version >= 766 { 0x08 } else { 0x07 };
+            assert_eq!(
+                buf.read_var_u32().0,
+                expected,
+                "protocol {}",
+                protocol.version
+            );
+            assert_eq!(buf.read_f32(), CHUNKS_PER_TICK);
+        }
+    }
+
+    /// A bot that keeps the chunks and entities of the world it left reports stale entity
+    /// counts and walks on blocks that aren't there
+    #[test]
+    fn respawns_are_handled() {
+        for protocol in PROTOCOLS {
+            let expected = match protocol.version {
+                764 => 0x43,
+                765 => 0x45,
+                _ => 0x47,
+            };
+            assert_eq!(
+                protocol.clientbound(ProtocolState::Play, expected),
+                Some(Clientbound::Respawn),
+                "protocol {}",
+                protocol.version
+            );
+        }
+        assert!(
+            packet_processors::lookup_packet(ProtocolState::Play, Clientbound::Respawn).is_some()
+        );
+    }
+}
@@ -1,16 +1,39 @@
 use crate::packet_utils::Buf;
-use crate::{Bot, Compression};
+use crate::{Bot, Context};
+use std::time::Instant;
 
-/// Status Response
-pub fn process_status_response(buffer: &mut Buf, _bot: &mut Bot, _compression: &mut Compression) {
-    let server_response = buffer.read_sized_string();
-    println!("got response {}", server_response)
+/// Disconnect reason of a status connection that got its ping answered
+pub const COMPLETE: &str = "status complete";
+
+/// Status Response, answered with a ping
+pub fn process_status_response(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let server_response = buffer
+        .read_sized_string()
+        .and_then(|response| Ok(serde_json::from_str::<serde_json::Value>(response)?));
+    match server_response {
+        Ok(_) => ctx.metrics.status_ok += 1,
+        Err(err) => {
+            println!("invalid status response: {}", err);
+            ctx.metrics.status_invalid += 1;
+        }
+    }
+
+    bot.ping_sent = Some(Instant::now());
+    bot.send_packet(write_ping(bot.id as u64), ctx);
 }
 
-/// Ping Response (status)
-pub fn process_pong(buffer: &mut Buf, _bot: &mut Bot, _compression: &mut Compression) {
-    let payload = buffer.read_sized_string();
-    println!("got pong {}", payload)
+/// Ping Response (status), the connection is done once it arrives
+pub fn process_pong(buffer: &mut Buf, bot: &mut Bot, ctx: &mut Context) {
+    let payload = buffer.read_u64();
+    if payload != bot.id as u64 {
+        bot.kick(format!("pong payload {} does not match the ping", payload));
+        return;
+    }
+
+    if let Some(ping_sent) = bot.ping_sent.take() {
+        ctx.metrics.ping_rtt.record_duration(ping_sent.elapsed());
+    }
+    bot.kick(COMPLETE);
 }
 
 /// Status Request
@@ -30,3 +53,55 @@ pub fn write_ping(payload: u64) -> Buf {
 
     buf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOLS;
+    use crate::settings::Settings;
+    use crate::ProtocolState;
+
+    fn status_response(json: &str) -> Buf {
+        let mut buf = Buf::new();
+        buf.write_sized_str(json);
+        buf
+    }
+
+    #[test]
+    fn status_responses_are_counted_and_pinged() {
+        let mut ctx = Context::for_protocol(&PROTOCOLS[0], Settings::default());
+        let (mut bot, mut peer) = Bot::paired(ProtocolState::Status);
+        bot.id = 9;
+        process_status_response(&mut status_response("{}"), &mut bot, &mut ctx);
+        process_status_response(&mut status_response("{"), &mut bot, &mut ctx);
+        process_status_response(&mut status_response("\u{fffd}"), &mut bot, &mut ctx);
+        assert_eq!((ctx.metrics.status_ok, ctx.metrics.status_invalid), (1, 2));
+        // Invalid responses are pinged as well, the server answered after all
+        for _ in 0..3 {
+            let mut ping = Bot::sent(&mut peer);
+            assert_eq!(ping.read_var_u32().0, 0x01);
+            assert_eq!(ping.read_u64(), 9);
+        }
+        assert!(bot.ping_sent.is_some() && !bot.kicked);
+    }
+
+    #[test]
+    fn the_pong_completes_the_connection() {
+        let mut ctx = Context::for_protocol(&PROTOCOLS[0], Settings::default());
+        let (mut bot, _peer) = Bot::paired(ProtocolState::Status);
+        bot.id = 9;
+        bot.ping_sent = Some(Instant::now());
+        let mut pong = Buf::new();
+        pong.write_u64(9);
+        process_pong(&mut pong, &mut bot, &mut ctx);
+        assert_eq!(bot.disconnect_reason.as_deref(), Some(COMPLETE));
+        assert_eq!(ctx.metrics.ping_rtt.count(), 1);
+
+        let (mut bot, _peer) = Bot::paired(ProtocolState::Status);
+        let mut pong = Buf::new();
+        pong.write_u64(10);
+        process_pong(&mut pong, &mut bot, &mut ctx);
+        assert!(bot.kicked);
+        assert_ne!(bot.disconnect_reason.as_deref(), Some(COMPLETE));
+    }
+}
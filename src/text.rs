@@ -0,0 +1,151 @@
+use crate::nbt::Nbt;
+use serde_json::Value;
+
+/// Flattens a text component sent as NBT (1.20.3+) into plain text
+pub fn nbt_to_plain(component: &Nbt) -> String {
+    let mut out = String::new();
+    append_nbt(&mut out, component);
+    out
+}
+
+/// Flattens a text component sent as JSON into plain text, invalid JSON is returned as is
+pub fn json_to_plain(component: &str) -> String {
+    match serde_json::from_str::<Value>(component) {
+        Ok(value) => {
+            let mut out = String::new();
+            append_json(&mut out, &value);
+            out
+        }
+        Err(_) => component.to_owned(),
+    }
+}
+
+fn append_nbt(out: &mut String, component: &Nbt) {
+    match component {
+        Nbt::String(text) => out.push_str(text),
+        Nbt::List(components) => components.iter().for_each(|c| append_nbt(out, c)),
+        Nbt::Compound(_) => {
+            // Lists of mixed types wrap their elements in a compound with an empty key
+            if let Some(text) = component.get("text").or_else(|| component.get("")) {
+                append_nbt(out, text);
+            } else if let Some(key) = component.get("translate").and_then(Nbt::as_str) {
+                out.push_str(key);
+                if let Some(Nbt::List(with)) = component.get("with") {
+                    out.push_str(" [");
+                    for (i, arg) in with.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        append_nbt(out, arg);
+                    }
+                    out.push(']');
+                }
+            }
+            if let Some(extra) = component.get("extra") {
+                append_nbt(out, extra);
+            }
+        }
+        Nbt::Byte(n) => out.push_str(&n.to_string()),
+        Nbt::Short(n) => out.push_str(&n.to_string()),
+        Nbt::Int(n) => out.push_str(&n.to_string()),
+        Nbt::Long(n) => out.push_str(&n.to_string()),
+        Nbt::Float(n) => out.push_str(&n.to_string()),
+        Nbt::Double(n) => out.push_str(&n.to_string()),
+        Nbt::ByteArray(_) | Nbt::IntArray(_) | Nbt::LongArray(_) => {}
+    }
+}
+
+fn append_json(out: &mut String, component: &Value) {
+    match component {
+        Value::String(text) => out.push_str(text),
+        Value::Array(components) => components.iter().for_each(|c| append_json(out, c)),
+        Value::Object(object) => {
+            if let Some(text) = object.get("text") {
+                append_json(out, text);
+            } else if let Some(Value::String(key)) = object.get("translate") {
+                out.push_str(key);
+                if let Some(Value::Array(with)) = object.get("with") {
+                    out.push_str(" [");
+                    for (i, arg) in with.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        append_json(out, arg);
+                    }
+                    out.push(']');
+                }
+            }
+            if let Some(extra) = object.get("extra") {
+                append_json(out, extra);
+            }
+        }
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::Bool(b) => out.push_str(&b.to_string()),
+        Value::Null => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> Nbt {
+        Nbt::String(text.to_owned())
+    }
+
+    #[test]
+    fn json_components_are_flattened() {
+        assert_eq!(json_to_plain(r#""plain""#), "plain");
+        assert_eq!(
+            json_to_plain(r#"{"text":"Hello ","extra":[{"text":"world","bold":true},"!"]}"#),
+            "Hello world!"
+        );
+        assert_eq!(
+            json_to_plain(r#"{"translate":"chat.type.text","with":["Bot_1",{"text":"hi"}]}"#),
+            "chat.type.text [Bot_1, hi]"
+        );
+        assert_eq!(json_to_plain(r#"[1,true,null,"x"]"#), "1truex");
+    }
+
+    #[test]
+    fn malformed_json_is_returned_as_is() {
+        assert_eq!(json_to_plain(""), "");
+        assert_eq!(json_to_plain("{\"text\":"), "{\"text\":");
+        assert_eq!(json_to_plain("not json"), "not json");
+        // Valid JSON without any text
+        assert_eq!(json_to_plain(r#"{"color":"red"}"#), "");
+    }
+
+    #[test]
+    fn nbt_components_are_flattened() {
+        assert_eq!(nbt_to_plain(&string("plain")), "plain");
+        let component = Nbt::Compound(vec![
+            ("text".to_owned(), string("Hello ")),
+            (
+                "extra".to_owned(),
+                Nbt::List(vec![
+                    Nbt::Compound(vec![("".to_owned(), string("world"))]),
+                    Nbt::Compound(vec![("".to_owned(), Nbt::Int(1))]),
+                ]),
+            ),
+        ]);
+        assert_eq!(nbt_to_plain(&component), "Hello world1");
+        let translated = Nbt::Compound(vec![
+            ("translate".to_owned(), string("death.attack.fall")),
+            ("with".to_owned(), Nbt::List(vec![string("Bot_1")])),
+        ]);
+        assert_eq!(nbt_to_plain(&translated), "death.attack.fall [Bot_1]");
+    }
+
+    #[test]
+    fn odd_nbt_components_are_skipped() {
+        assert_eq!(nbt_to_plain(&Nbt::IntArray(vec![1, 2])), "");
+        assert_eq!(nbt_to_plain(&Nbt::Compound(Vec::new())), "");
+        // A translate key that isn't a string and a `with` that isn't a list
+        let component = Nbt::Compound(vec![
+            ("translate".to_owned(), Nbt::Int(3)),
+            ("with".to_owned(), string("x")),
+        ]);
+        assert_eq!(nbt_to_plain(&component), "");
+    }
+}
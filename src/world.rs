@@ -0,0 +1,451 @@
+use crate::nbt;
+use crate::packet_utils::Buf;
+use crate::Error;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+
+/// Block state id of air, returned for blocks outside the world
+pub const AIR: u32 = 0;
+
+/// Block states of one 16x16x16 chunk section, kept packed the way the server sent them
+enum Section {
+    Single(u32),
+    /// `palette` is empty when the entries are block state ids
+    Packed {
+        bits: u32,
+        palette: Vec<u32>,
+        data: Vec<u64>,
+    },
+}
+
+impl Section {
+    /// Reads a paletted container, entries of up to `max_indirect` bits index the palette and
+    /// use at least `min_bits` bits
+    fn read(buf: &mut Buf, min_bits: u32, max_indirect: u32) -> Result<Section, Error> {
+        let bits = buf.read_byte() as u32;
+        if bits == 0 {
+            let state = buf.read_var_u32().0;
+            let length = Section::read_data_length(buf)?;
+            buf.read_bytes(length * 8);
+            return Ok(Section::Single(state));
+        }
+
+        let (bits, palette) = if bits <= max_indirect {
+            (bits.max(min_bits), buf.read_var_u32_slice()?)
+        } else {
+            (bits, Vec::new())
+        };
+        let length = Section::read_data_length(buf)?;
+        let data = (0..length).map(|_| buf.read_u64()).collect();
+        Ok(Section::Packed {
+            bits: bits.min(32),
+            palette,
+            data,
+        })
+    }
+
+    /// Reads the number of longs in the data array, which has to fit in the packet
+    fn read_data_length(buf: &mut Buf) -> Result<u32, Error> {
+        let length = buf.read_var_u32().0;
+        if length as u64 * 8 > buf.remaining() as u64 {
+            return Err(format!("section of {} longs is longer than the packet", length).into());
+        }
+        Ok(length)
+    }
+
+    /// Packs 4096 block states with `bits` bits per entry, indexing `palette` unless it is empty
+    fn pack(states: &[u32], bits: u32, palette: Vec<u32>) -> Section {
+        let per_long = (64 / bits) as usize;
+        let mut data = vec![0u64; states.len().div_ceil(per_long)];
+        for (index, state) in states.iter().enumerate() {
+            let entry = if palette.is_empty() {
+                *state
+            } else {
+                palette.iter().position(|known| known == state).unwrap_or(0) as u32
+            };
+            data[index / per_long] |= (entry as u64) << ((index % per_long) as u32 * bits);
+        }
+        Section::Packed {
+            bits,
+            palette,
+            data,
+        }
+    }
+
+    fn set(&mut self, index: usize, state: u32) {
+        if self.get(index) == state {
+            return;
+        }
+        if let Section::Packed {
+            bits,
+            palette,
+            data,
+        } = self
+        {
+            let entry = if palette.is_empty() {
+                state
+            } else if let Some(entry) = palette.iter().position(|known| *known == state) {
+                entry as u32
+            } else {
+                palette.push(state);
+                palette.len() as u32 - 1
+            };
+            let per_long = (64 / *bits) as usize;
+            let shift = (index % per_long) as u32 * *bits;
+            match data.get_mut(index / per_long) {
+                Some(long) if entry >> *bits == 0 => {
+                    *long = *long & !(((1u64 << *bits) - 1) << shift) | (entry as u64) << shift;
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        // The section outgrew its layout, unpack it and pack it again with more bits
+        let mut states: Vec<u32> = (0..4096).map(|index| self.get(index)).collect();
+        states[index] = state;
+        let mut palette: Vec<u32> = Vec::new();
+        for state in &states {
+            if !palette.contains(state) {
+                palette.push(*state);
+            }
+        }
+        let bits = (32 - (palette.len() as u32 - 1).leading_zeros()).max(4);
+        *self = if bits <= 8 {
+            Section::pack(&states, bits, palette)
+        } else {
+            let highest = states.iter().max().copied().unwrap_or(AIR);
+            Section::pack(&states, 32 - highest.leading_zeros(), Vec::new())
+        };
+    }
+
+    /// Entries don't span longs, the first one is in the lowest bits
+    fn get(&self, index: usize) -> u32 {
+        match self {
+            Section::Single(state) => *state,
+            Section::Packed {
+                bits,
+                palette,
+                data,
+            } => {
+                let per_long = (64 / bits) as usize;
+                let long = match data.get(index / per_long) {
+                    Some(long) => *long,
+                    None => return AIR,
+                };
+                let entry = (long >> ((index % per_long) as u32 * bits)) & ((1 << bits) - 1);
+                if palette.is_empty() {
+                    entry as u32
+                } else {
+                    palette.get(entry as usize).copied().unwrap_or(AIR)
+                }
+            }
+        }
+    }
+}
+
+struct Chunk {
+    min_y: i32,
+    sections: Vec<Section>,
+    /// Bots that have the chunk loaded, it is dropped once the last one unloads it
+    viewers: u32,
+}
+
+/// Chunk x and z
+pub type ChunkPos = (i32, i32);
+
+/// The chunks received by the bots of one worker thread.
+///
+/// Bots on the same thread share it, so a chunk sent to several of them is only kept and
+/// parsed once. Every bot tracks the chunks it has loaded in `Bot::chunks`, a chunk is dropped
+/// when no bot has it loaded anymore. Chunks are keyed by their position only, so the bots of a
+/// thread are assumed to be in the same dimension of the same server.
+pub struct World {
+    chunks: HashMap<ChunkPos, Chunk>,
+    /// The states of the protocol's air blocks
+    air_states: &'static [u32],
+    /// Other states without a collision box, every block not in either list counts as solid
+    non_colliding: &'static [RangeInclusive<u32>],
+}
+
+impl World {
+    pub fn new(air_states: &'static [u32], non_colliding: &'static [RangeInclusive<u32>]) -> World {
+        World {
+            chunks: HashMap::new(),
+            air_states,
+            non_colliding,
+        }
+    }
+
+    /// Reads the blocks of a Chunk Data and Update Light packet for the bot with the chunks
+    /// `loaded`. A chunk another bot has loaded is already up to date and isn't read again.
+    pub fn load_chunk(&mut self, buf: &mut Buf, loaded: &mut HashSet<ChunkPos>) {
+        let x = buf.read_u32() as i32;
+        let z = buf.read_u32() as i32;
+        let new_viewer = loaded.insert((x, z));
+        if let Some(chunk) = self.chunks.get_mut(&(x, z)) {
+            if new_viewer {
+                chunk.viewers += 1;
+            }
+            if chunk.viewers > 1 {
+                return;
+            }
+        }
+
+        let read = nbt::read_network_nbt(buf).and_then(|_heightmaps| World::read_sections(buf));
+        let sections = match read {
+            Ok(sections) => sections,
+            Err(_) => {
+                // A chunk that can't be read stays unloaded, or keeps its old blocks
+                if !self.chunks.contains_key(&(x, z)) {
+                    loaded.remove(&(x, z));
+                }
+                return;
+            }
+        };
+
+        // The height of the dimension is only sent with the registries, the overworld is the
+        // only vanilla dimension with 24 sections and starts at y -64
+        let min_y = if sections.len() == 24 { -64 } else { 0 };
+        self.chunks.insert(
+            (x, z),
+            Chunk {
+                min_y,
+                sections,
+                viewers: 1,
+            },
+        );
+    }
+
+    fn read_sections(buf: &mut Buf) -> Result<Vec<Section>, Error> {
+        let size = buf.read_var_u32().0;
+        if size > buf.remaining() {
+            return Err(format!("chunk data of {} bytes is longer than the packet", size).into());
+        }
+        let end = buf.get_reader_index() + size;
+
+        let mut sections = Vec::new();
+        while buf.get_reader_index() < end {
+            let _block_count = buf.read_u16();
+            sections.push(Section::read(buf, 4, 8)?);
+            let _biomes = Section::read(buf, 1, 3)?;
+        }
+        buf.set_reader_index(end);
+        Ok(sections)
+    }
+
+    /// The bot with the chunks `loaded` unloaded the chunk
+    pub fn unload_chunk(&mut self, pos: ChunkPos, loaded: &mut HashSet<ChunkPos>) {
+        if !loaded.remove(&pos) {
+            return;
+        }
+        if let Some(chunk) = self.chunks.get_mut(&pos) {
+            chunk.viewers -= 1;
+            if chunk.viewers == 0 {
+                self.chunks.remove(&pos);
+            }
+        }
+    }
+
+    /// Unloads every chunk of a bot that disconnected or moves to another server
+    pub fn unload_all(&mut self, loaded: &mut HashSet<ChunkPos>) {
+        for pos in loaded.clone() {
+            self.unload_chunk(pos, loaded);
+        }
+    }
+
+    /// Block state at the given block position, `None` if its chunk isn't loaded. Blocks above
+    /// and below the world are air.
+    pub fn block(&self, x: i32, y: i32, z: i32) -> Option<u32> {
+        let chunk = self.chunks.get(&(x >> 4, z >> 4))?;
+        let section = (y - chunk.min_y) >> 4;
+        if section < 0 {
+            return Some(AIR);
+        }
+        let state = chunk
+            .sections
+            .get(section as usize)
+            .map_or(AIR, |section| section.get(Self::index(x, y, z)));
+        Some(state)
+    }
+
+    /// Changes a block, blocks in chunks that aren't loaded or outside the world are ignored
+    pub fn set_block(&mut self, x: i32, y: i32, z: i32, state: u32) {
+        if let Some(chunk) = self.chunks.get_mut(&(x >> 4, z >> 4)) {
+            let section = (y - chunk.min_y) >> 4;
+            if section >= 0 {
+                if let Some(section) = chunk.sections.get_mut(section as usize) {
+                    section.set(Self::index(x, y, z), state);
+                }
+            }
+        }
+    }
+
+    /// Whether the block collides with players, `None` if its chunk isn't loaded
+    pub fn is_solid(&self, x: i32, y: i32, z: i32) -> Option<bool> {
+        self.block(x, y, z).map(|state| {
+            !self.air_states.contains(&state)
+                && !self
+                    .non_colliding
+                    .iter()
+                    .any(|states| states.contains(&state))
+        })
+    }
+
+    /// Index of a block in its section
+    fn index(x: i32, y: i32, z: i32) -> usize {
+        (((y & 15) << 8) | ((z & 15) << 4) | (x & 15)) as usize
+    }
+}
+
+#[cfg(test)]
+impl World {
+    /// Loads an overworld chunk of air for building worlds by hand
+    pub fn load_empty_chunk(&mut self, pos: ChunkPos) {
+        let sections = (0..24).map(|_| Section::Single(AIR)).collect();
+        self.chunks.insert(
+            pos,
+            Chunk {
+                min_y: -64,
+                sections,
+                viewers: 1,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    /// Bits per entry and whether they index a palette
+    fn layout(section: &Section) -> Option<(u32, bool)> {
+        match section {
+            Section::Single(_) => None,
+            Section::Packed { bits, palette, .. } => Some((*bits, !palette.is_empty())),
+        }
+    }
+
+    fn assert_states(section: &Section, states: &[u32]) {
+        for (index, state) in states.iter().enumerate() {
+            assert_eq!(section.get(index), *state, "block {}", index);
+        }
+    }
+
+    #[test]
+    fn sections_repack_as_their_palette_grows() {
+        let mut section = Section::Single(AIR);
+        let mut states = vec![AIR; 4096];
+        section.set(0, AIR);
+        assert_eq!(layout(&section), None);
+
+        section.set(100, 7);
+        states[100] = 7;
+        assert_eq!(layout(&section), Some((4, true)));
+        for (i, index) in (1..15).zip((0..4096).step_by(97)) {
+            section.set(index, 1000 + i);
+            states[index] = 1000 + i;
+        }
+        assert_eq!(layout(&section), Some((4, true)));
+        assert_states(&section, &states);
+
+        // Air, 7 and those 14 fill the 4 bit palette, a 17th state needs 5 bits
+        section.set(4095, 3);
+        states[4095] = 3;
+        assert_eq!(layout(&section), Some((5, true)));
+        assert_states(&section, &states);
+
+        // More than 256 states are stored directly, with as many bits as the highest one needs
+        for (index, state) in states.iter_mut().enumerate().skip(1000).take(300) {
+            *state = 2000 + index as u32;
+            section.set(index, *state);
+        }
+        assert_eq!(layout(&section), Some((12, false)));
+        assert_states(&section, &states);
+        section.set(5, 20000);
+        states[5] = 20000;
+        assert_eq!(layout(&section), Some((15, false)));
+        assert_states(&section, &states);
+    }
+
+    #[test]
+    fn sections_drop_unused_palette_entries_when_repacking() {
+        let mut section = Section::Single(AIR);
+        // Every state replaces the last one, so only two are ever in use
+        for state in 1..=40 {
+            section.set(10, state);
+            assert_eq!(section.get(10), state);
+            assert_eq!(section.get(11), AIR);
+        }
+        assert_eq!(layout(&section), Some((4, true)));
+    }
+
+    #[test]
+    fn sections_are_read_as_sent() {
+        let mut buf = Buf::new();
+        // A single state, with an empty data array
+        buf.write_u8(0);
+        buf.write_var_u32(9);
+        buf.write_var_u32(0);
+        // Palette of two with 1 bit entries, read with at least 4 bits
+        buf.write_u8(1);
+        buf.write_var_u32(2);
+        buf.write_var_u32(AIR);
+        buf.write_var_u32(33);
+        buf.write_var_u32(256);
+        for _ in 0..256 {
+            buf.write_u64(0x0000_0000_0000_0010);
+        }
+        // Direct entries of 15 bits, 4 per long
+        buf.write_u8(15);
+        buf.write_var_u32(1024);
+        for _ in 0..1024 {
+            buf.write_u64(20000 << 15);
+        }
+
+        assert_states(&Section::read(&mut buf, 4, 8).unwrap(), &[9; 4096]);
+        let palette = Section::read(&mut buf, 4, 8).unwrap();
+        assert_eq!(layout(&palette), Some((4, true)));
+        assert_states(&palette, &[AIR, 33, AIR, AIR, AIR, AIR]);
+        assert_eq!(palette.get(4096 - 15), 33);
+        let direct = Section::read(&mut buf, 4, 8).unwrap();
+        assert_eq!(layout(&direct), Some((15, false)));
+        assert_states(&direct, &[AIR, 20000, AIR, AIR, AIR, 20000]);
+        assert_eq!(buf.remaining(), 0);
+
+        // Data arrays longer than the packet
+        let mut short = Buf::new();
+        short.write_u8(4);
+        short.write_var_u32(1);
+        short.write_var_u32(AIR);
+        short.write_var_u32(256);
+        short.write_u64(0);
+        assert!(Section::read(&mut short, 4, 8).is_err());
+    }
+
+    #[test]
+    fn only_blocks_with_a_collision_box_are_solid() {
+        for protocol in protocol::PROTOCOLS {
+            let mut world = World::new(protocol.air_states, protocol.non_colliding);
+            world.load_empty_chunk((0, 0));
+            // Stone, cave air, water, lava, short grass, a poppy, one and two snow layers
+            for (state, solid) in [
+                (1, true),
+                (12818, false),
+                (80, false),
+                (111, false),
+                (2005, false),
+                (2077, false),
+                (5772, false),
+                (5773, true),
+            ] {
+                world.set_block(3, 70, 5, state);
+                assert_eq!(world.block(3, 70, 5), Some(state));
+                assert_eq!(world.is_solid(3, 70, 5), Some(solid), "state {}", state);
+            }
+            assert_eq!(world.is_solid(3, -100, 5), Some(false));
+            assert_eq!(world.is_solid(16, 70, 5), None);
+        }
+    }
+}